@@ -0,0 +1,104 @@
+// With the `embed` feature, bundles static/ and templates/ into the binary:
+// generates `assets.rs` in OUT_DIR listing every file with an ETag, its
+// modification time and, for static files, gzip and brotli variants.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "embed")]
+    embed::generate();
+}
+
+#[cfg(feature = "embed")]
+mod embed {
+    use std::collections::hash_map::DefaultHasher;
+    use std::fmt::Write as _;
+    use std::hash::{Hash, Hasher};
+    use std::io::Write as _;
+    use std::path::{Path, PathBuf};
+    use std::time::UNIX_EPOCH;
+
+    // A variant is only kept when it saves at least this share of the original
+    const MIN_SAVING: f64 = 0.1;
+
+    pub fn generate() {
+        let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+        let out = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        let mut code = String::new();
+        for (name, dir, compress) in [("STATIC_FILES", "static", true), ("TEMPLATE_FILES", "templates", false)] {
+            let dir = root.join(dir);
+            println!("cargo:rerun-if-changed={}", dir.display());
+            let mut files = Vec::new();
+            collect(&dir, &mut files);
+            files.sort();
+            writeln!(code, "pub static {}: &[Embedded] = &[", name).unwrap();
+            for path in files {
+                println!("cargo:rerun-if-changed={}", path.display());
+                code.push_str(&entry(&dir, &path, compress.then_some(out.as_path())));
+            }
+            code.push_str("];\n");
+        }
+        std::fs::write(out.join("assets.rs"), code).unwrap();
+    }
+
+    fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(dir) else { return };
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.is_dir() {
+                collect(&path, files);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    /// One `Embedded` literal; compressed variants are written to `out` when given.
+    fn entry(dir: &Path, path: &Path, out: Option<&Path>) -> String {
+        let body = std::fs::read(path).unwrap();
+        let relative = path.strip_prefix(dir).unwrap().to_string_lossy().replace('\\', "/");
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let modified = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let etag = hasher.finish();
+        let (gzip, br) = match out {
+            Some(out) => compress(&body, etag, out),
+            None => ("None".to_string(), "None".to_string()),
+        };
+        format!(
+            "    Embedded {{ path: {:?}, body: include_bytes!({:?}), gzip: {}, br: {}, etag: \"{:016x}\", modified: {} }},\n",
+            relative,
+            path.display().to_string(),
+            gzip,
+            br,
+            etag,
+            modified,
+        )
+    }
+
+    fn compress(body: &[u8], etag: u64, out: &Path) -> (String, String) {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(body).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut br = Vec::new();
+        brotli::BrotliCompress(&mut &body[..], &mut br, &brotli::enc::BrotliEncoderParams {
+            quality: 11,
+            ..Default::default()
+        })
+        .unwrap();
+        (
+            variant(body, gzip, &out.join(format!("{:016x}.gz", etag))),
+            variant(body, br, &out.join(format!("{:016x}.br", etag))),
+        )
+    }
+
+    fn variant(body: &[u8], compressed: Vec<u8>, file: &Path) -> String {
+        if (compressed.len() as f64) > body.len() as f64 * (1.0 - MIN_SAVING) {
+            return "None".to_string();
+        }
+        std::fs::write(file, compressed).unwrap();
+        format!("Some(include_bytes!({:?}))", file.display().to_string())
+    }
+}
@@ -0,0 +1,147 @@
+use actix_files::NamedFile;
+use actix_web::http::header::{self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch};
+use actix_web::{route, web, HttpMessage, HttpRequest, HttpResponse};
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// A file compiled into the binary by build.rs, with precompressed variants
+/// when they are worth it.
+#[cfg_attr(not(feature = "embed"), allow(dead_code))]
+pub struct Embedded {
+    path: &'static str,
+    body: &'static [u8],
+    gzip: Option<&'static [u8]>,
+    br: Option<&'static [u8]>,
+    etag: &'static str,
+    /// Seconds since the Unix epoch.
+    modified: u64,
+}
+
+#[cfg(feature = "embed")]
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+#[cfg(feature = "embed")]
+fn embedded(path: &str) -> Option<&'static Embedded> {
+    STATIC_FILES.iter().find(|f| f.path == path)
+}
+
+#[cfg(not(feature = "embed"))]
+fn embedded(_path: &str) -> Option<&'static Embedded> {
+    None
+}
+
+/// Templates compiled into the binary, as `(name, source)` pairs.
+#[cfg(feature = "embed")]
+pub fn embedded_templates() -> Vec<(&'static str, &'static str)> {
+    TEMPLATE_FILES.iter()
+        .filter_map(|f| std::str::from_utf8(f.body).ok().map(|source| (f.path, source)))
+        .collect()
+}
+
+#[cfg(not(feature = "embed"))]
+pub fn embedded_templates() -> Vec<(&'static str, &'static str)> {
+    Vec::new()
+}
+
+/// Files served under `/static`: from `dir` when set and the file exists
+/// there, otherwise from the copy embedded in the binary.
+pub struct StaticFiles {
+    dir: Option<PathBuf>,
+}
+
+impl StaticFiles {
+    pub fn new(dir: Option<&str>) -> Self {
+        StaticFiles { dir: dir.map(PathBuf::from) }
+    }
+
+    fn on_disk(&self, path: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let relative = Path::new(path);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        let full = dir.join(relative);
+        full.is_file().then_some(full)
+    }
+
+    pub fn read(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match self.on_disk(path) {
+            Some(full) => std::fs::read(full).ok().map(Cow::Owned),
+            None => embedded(path).map(|f| Cow::Borrowed(f.body)),
+        }
+    }
+}
+
+#[route("/static/{path:.*}", method = "GET", method = "HEAD")]
+pub async fn static_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    files: web::Data<Arc<StaticFiles>>,
+) -> actix_web::Result<HttpResponse> {
+    if let Some(full) = files.on_disk(&path) {
+        return Ok(NamedFile::open(full)?.into_response(&req));
+    }
+    Ok(match embedded(&path) {
+        Some(file) => serve(&req, file),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+fn serve(req: &HttpRequest, file: &'static Embedded) -> HttpResponse {
+    let (body, encoding) = match (file.br, file.gzip) {
+        (Some(br), _) if accepts(req, "br") => (br, Some("br")),
+        (_, Some(gzip)) if accepts(req, "gzip") => (gzip, Some("gzip")),
+        _ => (file.body, None),
+    };
+    // Each encoding is its own representation, so needs its own strong tag
+    let etag = EntityTag::new_strong(match encoding {
+        Some(encoding) => format!("{}-{}", file.etag, encoding),
+        None => file.etag.to_string(),
+    });
+    let modified = HttpDate::from(UNIX_EPOCH + Duration::from_secs(file.modified));
+    if not_modified(req, &etag, modified) {
+        return HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header(header::LastModified(modified))
+            .insert_header((header::VARY, "Accept-Encoding"))
+            .finish();
+    }
+
+    let ext = Path::new(file.path).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let mut content_type = actix_files::file_extension_to_mime(ext).to_string();
+    // Same as NamedFile, so a file reads the same embedded or from disk
+    if content_type.starts_with("text/") || content_type.ends_with("/javascript") || content_type.ends_with("/json") {
+        content_type.push_str("; charset=utf-8");
+    }
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(content_type)
+        .insert_header(header::ETag(etag))
+        .insert_header(header::LastModified(modified))
+        .insert_header((header::VARY, "Accept-Encoding"));
+    if let Some(encoding) = encoding {
+        resp.insert_header((header::CONTENT_ENCODING, encoding));
+    }
+    resp.body(body)
+}
+
+fn not_modified(req: &HttpRequest, etag: &EntityTag, modified: HttpDate) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+        None => req.get_header::<IfModifiedSince>().is_some_and(|IfModifiedSince(since)| modified <= since),
+    }
+}
+
+/// Whether `Accept-Encoding` lists `encoding` without `q=0`.
+fn accepts(req: &HttpRequest, encoding: &str) -> bool {
+    let Some(accepted) = req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accepted.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        parts.next() == Some(encoding)
+            && parts.all(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()).is_none_or(|q| q > 0.0))
+    })
+}
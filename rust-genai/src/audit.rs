@@ -0,0 +1,229 @@
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::providers::Usage;
+use crate::redact::Redactor;
+
+// Records waiting for the writer thread; past this they are dropped rather
+// than holding up requests while the disk is slow
+const QUEUE_LEN: usize = 10_000;
+
+/// One answered chat request, as written to the audit log.
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    pub timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The rate-limit and quota identity: API key hash or client IP.
+    pub client: &'a str,
+    /// Name of the API key or session the request authenticated with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<&'a str>,
+    pub endpoint: &'a str,
+    pub model: &'a str,
+    pub prompt: &'a str,
+    pub response: &'a str,
+    pub cached: bool,
+    pub latency_ms: u64,
+    pub usage: Option<&'a Usage>,
+}
+
+impl<'a> AuditRecord<'a> {
+    pub fn new(endpoint: &'a str, client: &'a str, principal: Option<&'a str>, model: &'a str, started: Instant) -> Self {
+        AuditRecord {
+            timestamp: Utc::now(),
+            request_id: crate::telemetry::current_request_id(),
+            client,
+            principal,
+            endpoint,
+            model,
+            prompt: "",
+            response: "",
+            cached: false,
+            latency_ms: started.elapsed().as_millis() as u64,
+            usage: None,
+        }
+    }
+}
+
+/// Append-only record of what was asked and answered, one JSON object per
+/// line in a file per UTC day (`audit-YYYY-MM-DD.jsonl`). Secrets and PII
+/// matching `Redactor::for_audit` are replaced before anything is written,
+/// and files older than the retention period are deleted. Files are only
+/// touched by a writer thread fed over a channel, so requests never wait on
+/// the disk.
+pub struct AuditLog {
+    queue: Option<SyncSender<(NaiveDate, Vec<u8>)>>,
+    writer: Option<JoinHandle<()>>,
+    dir: PathBuf,
+    redactor: Option<Redactor>,
+    retention_days: u64,
+}
+
+struct Sink {
+    dir: PathBuf,
+    file: Option<(NaiveDate, File)>,
+}
+
+impl AuditLog {
+    /// Records nothing.
+    pub fn disabled() -> Self {
+        AuditLog { queue: None, writer: None, dir: PathBuf::new(), redactor: None, retention_days: 0 }
+    }
+
+    /// Writes to `dir`, creating it if needed. `retention_days` of 0 keeps
+    /// records forever.
+    pub fn new(dir: &str, redactor: Redactor, retention_days: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let (queue, lines) = mpsc::sync_channel::<(NaiveDate, Vec<u8>)>(QUEUE_LEN);
+        let mut sink = Sink { dir: PathBuf::from(dir), file: None };
+        let writer = thread::Builder::new().name("audit-writer".to_string()).spawn(move || {
+            // Ends once the log is dropped and the queue drained
+            for (day, line) in lines {
+                if let Err(e) = sink.append(day, &line) {
+                    log::error!("Writing audit record failed: {}", e);
+                }
+            }
+        })?;
+        Ok(AuditLog { queue: Some(queue), writer: Some(writer), dir: PathBuf::from(dir), redactor: Some(redactor), retention_days })
+    }
+
+    /// Queues `record` with its prompt and response redacted. Failing to
+    /// write is logged rather than failing the request.
+    pub fn record(&self, record: AuditRecord<'_>) {
+        let (Some(queue), Some(redactor)) = (&self.queue, &self.redactor) else { return };
+        let prompt = redactor.scrub(record.prompt);
+        let response = redactor.scrub(record.response);
+        let record = AuditRecord { prompt: &prompt, response: &response, ..record };
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        match queue.try_send((record.timestamp.date_naive(), line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::error!("Audit writer is {} records behind; dropped one", QUEUE_LEN),
+            Err(TrySendError::Disconnected(_)) => log::error!("Audit writer has stopped; dropped a record"),
+        }
+    }
+
+    /// Deletes the files of days past the retention period.
+    pub fn purge_expired(&self) {
+        if self.queue.is_none() || self.retention_days == 0 {
+            return;
+        }
+        let Some(cutoff) = Utc::now().date_naive().checked_sub_days(Days::new(self.retention_days)) else { return };
+        let dir = &self.dir;
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Listing audit directory {} failed: {}", dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(day) = name.to_str().and_then(file_day) else { continue };
+            if day < cutoff {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => log::info!("Purged audit records of {}", day),
+                    Err(e) => log::error!("Purging {} failed: {}", entry.path().display(), e),
+                }
+            }
+        }
+    }
+
+    pub fn spawn_cleanup(self: &Arc<Self>, every: Duration) {
+        let audit = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                audit.purge_expired();
+            }
+        });
+    }
+}
+
+/// Waits for queued records to be written, so none are lost at shutdown.
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Sink {
+    /// Appends to the file of `day`, rolling over when the day changes.
+    fn append(&mut self, day: NaiveDate, line: &[u8]) -> io::Result<()> {
+        if self.file.as_ref().is_none_or(|(open, _)| *open != day) {
+            let path = self.dir.join(format!("audit-{}.jsonl", day.format("%Y-%m-%d")));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.file = Some((day, file));
+        }
+        let (_, file) = self.file.as_mut().unwrap();
+        file.write_all(line)
+    }
+}
+
+/// The day an audit file holds, from its name.
+fn file_day(name: &str) -> Option<NaiveDate> {
+    let day = name.strip_prefix("audit-")?.strip_suffix(".jsonl")?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("genai-audit-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn writes_scrubbed_records_from_the_writer_thread() {
+        let dir = temp_dir();
+        let log = AuditLog::new(dir.to_str().unwrap(), Redactor::builtin(false), 0).unwrap();
+        log.record(AuditRecord {
+            prompt: "mail jane@example.org",
+            response: "done",
+            ..AuditRecord::new("/api/chat", "ip:10.0.0.1", None, "m", Instant::now())
+        });
+        // Dropping waits for the queue to drain
+        drop(log);
+        let path = dir.join(format!("audit-{}.jsonl", Utc::now().date_naive().format("%Y-%m-%d")));
+        let written = fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(written.trim_end()).unwrap();
+        assert_eq!(record["prompt"], "mail [REDACTED]");
+        assert_eq!(record["endpoint"], "/api/chat");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn purges_only_expired_audit_files() {
+        let dir = temp_dir();
+        let log = AuditLog::new(dir.to_str().unwrap(), Redactor::builtin(false), 7).unwrap();
+        let today = Utc::now().date_naive();
+        let name = |days: u64| format!("audit-{}.jsonl", today.checked_sub_days(Days::new(days)).unwrap().format("%Y-%m-%d"));
+        for file in [name(8), name(7), "notes.txt".to_string()] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        log.purge_expired();
+        assert!(!dir.join(name(8)).exists());
+        assert!(dir.join(name(7)).exists());
+        assert!(dir.join("notes.txt").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reads_the_day_from_file_names() {
+        assert_eq!(file_day("audit-2024-02-29.jsonl"), NaiveDate::from_ymd_opt(2024, 2, 29));
+        assert_eq!(file_day("audit-2024-02-30.jsonl"), None);
+        assert_eq!(file_day("other-2024-02-29.jsonl"), None);
+    }
+}
@@ -0,0 +1,252 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::client::ClientId;
+use crate::error::ApiError;
+
+pub const SESSION_COOKIE: &str = "genai_session";
+
+// What the web UI calls; its sessions reach nothing else
+const WEB_UI_PATHS: &[&str] = &["/api/chat", "/api/conversations", "/api/models", "/api/personas"];
+
+/// What an API key may do. `admin` implies everything; `chat` implies
+/// `read-only`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Chat,
+    Admin,
+    ReadOnly,
+}
+
+impl Scope {
+    fn permits(self, required: Scope) -> bool {
+        match self {
+            Scope::Admin => true,
+            Scope::Chat => required != Scope::Admin,
+            Scope::ReadOnly => required == Scope::ReadOnly,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Chat => write!(f, "chat"),
+            Scope::Admin => write!(f, "admin"),
+            Scope::ReadOnly => write!(f, "read-only"),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "chat" => Ok(Scope::Chat),
+            "admin" => Ok(Scope::Admin),
+            "read-only" | "readonly" => Ok(Scope::ReadOnly),
+            other => Err(format!("unknown scope '{}'", other)),
+        }
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// An API key as stored: only the SHA-256 of the secret is kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub key_sha256: String,
+    pub scopes: Vec<Scope>,
+    /// Models the key may use; empty means any.
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    /// Parses `name:secret:scope+scope[:model+model]`, hashing the secret
+    /// straight away so it is never held in config.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(4, ':');
+        let (Some(name), Some(secret), Some(scopes)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("API key must look like name:secret:scope".to_string());
+        };
+        if name.is_empty() || secret.is_empty() {
+            return Err(format!("API key '{}' needs a name and a secret", name));
+        }
+        let scopes = scopes.split('+').map(str::parse).collect::<Result<Vec<_>, _>>()?;
+        let models = parts.next()
+            .map(|m| m.split('+').filter(|m| !m.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        Ok(ApiKey { name: name.to_string(), key_sha256: hash_key(secret), scopes, models })
+    }
+}
+
+/// Whoever a request was authenticated as. Inserted into request extensions
+/// by the `Authenticate` middleware.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    /// What the request is rate-limited and billed as.
+    pub client: ClientId,
+    scopes: Vec<Scope>,
+    models: Vec<String>,
+    /// Path prefixes the principal may call; empty means any.
+    paths: &'static [&'static str],
+}
+
+impl Principal {
+    /// The browser UI, through a server-issued session cookie.
+    fn web_ui(token: &str) -> Self {
+        Principal { name: "web-ui".to_string(), client: ClientId::for_session(token), scopes: vec![Scope::Chat], models: Vec::new(), paths: WEB_UI_PATHS }
+    }
+
+    pub fn allows_path(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|p| path == *p || path.strip_prefix(p).is_some_and(|rest| rest.starts_with('/')))
+    }
+
+    pub fn allows(&self, required: Scope) -> bool {
+        self.scopes.iter().any(|s| s.permits(required))
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model)
+    }
+}
+
+/// Rejects keys whose model allow-list doesn't cover the model in use.
+pub fn check_model(principal: Option<&Principal>, model: &str) -> Result<(), ApiError> {
+    match principal {
+        Some(p) if !p.allows_model(model) => {
+            Err(ApiError::Forbidden(format!("API key '{}' may not use model '{}'", p.name, model)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Configured API keys, looked up by hash, plus the sessions handed out to
+/// the web UI. With no keys configured authentication is off.
+pub struct KeyStore {
+    keys: HashMap<String, ApiKey>,
+    sessions: DashMap<String, Instant>,
+    session_ttl: Duration,
+    max_sessions: usize,
+}
+
+impl KeyStore {
+    pub fn new(keys: Vec<ApiKey>, session_ttl: Duration, max_sessions: usize) -> Self {
+        KeyStore {
+            keys: keys.into_iter().map(|k| (k.key_sha256.to_ascii_lowercase(), k)).collect(),
+            sessions: DashMap::new(),
+            session_ttl,
+            max_sessions,
+        }
+    }
+
+    /// Reads a JSON array of `ApiKey`s, for keys kept out of the environment.
+    pub fn load_file(path: &str) -> std::io::Result<Vec<ApiKey>> {
+        let raw = std::fs::read(path)?;
+        serde_json::from_slice(&raw).map_err(std::io::Error::other)
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Identities that get their own rate-limit and quota accounting.
+    pub fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.keys.keys().map(|hash| ClientId::for_key_hash(hash))
+    }
+
+    /// Resolves per-key policies, configured by key name, to the identities
+    /// they apply to. Names matching no configured key are errors.
+    pub fn by_name<P>(&self, setting: &str, policies: HashMap<String, P>) -> Result<HashMap<ClientId, P>, String> {
+        policies.into_iter()
+            .map(|(name, policy)| {
+                self.keys.iter()
+                    .find(|(_, k)| k.name == name)
+                    .map(|(hash, _)| (ClientId::for_key_hash(hash), policy))
+                    .ok_or_else(|| format!("{}: no API key named '{}'", setting, name))
+            })
+            .collect()
+    }
+
+    pub fn authenticate_key(&self, key: &str) -> Option<Principal> {
+        let hash = hash_key(key);
+        self.keys.get(&hash).map(|k| Principal {
+            name: k.name.clone(),
+            client: ClientId::for_key_hash(&hash),
+            scopes: k.scopes.clone(),
+            models: k.models.clone(),
+            paths: &[],
+        })
+    }
+
+    pub fn authenticate_session(&self, token: &str) -> Option<Principal> {
+        let valid = self.sessions.get(token).is_some_and(|expires| *expires > Instant::now());
+        valid.then(|| Principal::web_ui(token))
+    }
+
+    /// Starts a web UI session and returns its token, or `None` while
+    /// `max_sessions` are alive.
+    pub fn create_session(&self) -> Option<String> {
+        if self.sessions.len() >= self.max_sessions {
+            self.purge_expired_sessions();
+            if self.sessions.len() >= self.max_sessions {
+                return None;
+            }
+        }
+        let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        self.sessions.insert(token.clone(), Instant::now() + self.session_ttl);
+        Some(token)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    pub fn purge_expired_sessions(&self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, expires| *expires > now);
+    }
+
+    /// Runs `purge_expired_sessions` every `every` on the current runtime.
+    pub fn spawn_cleanup(self: &Arc<Self>, every: Duration) {
+        let store = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                store.purge_expired_sessions();
+            }
+        });
+    }
+}
+
+/// Scope needed for an API call: chatting and changing conversations need
+/// `chat`, operator endpoints need `admin`, other reads need `read-only`.
+pub fn required_scope(method: &actix_web::http::Method, path: &str) -> Scope {
+    if path.starts_with("/api/cache") {
+        Scope::Admin
+    } else if path.starts_with("/api/chat") || path.starts_with("/v1/chat") || method != actix_web::http::Method::GET {
+        Scope::Chat
+    } else {
+        Scope::ReadOnly
+    }
+}
+
+/// Whether a path is behind authentication. The docs page is static HTML
+/// and stays public.
+pub fn is_protected(path: &str) -> bool {
+    (path.starts_with("/api/") && path != "/api/docs") || path.starts_with("/v1/")
+}
@@ -0,0 +1,492 @@
+use async_trait::async_trait;
+use futures::future::{join_all, BoxFuture};
+use futures::{FutureExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::redact_url;
+use crate::conversation::ChatMessage;
+use crate::metrics::{Metrics, StreamTimer};
+use crate::params::GenerationParams;
+use crate::providers::{Completion, EventStream, LlmProvider, ProviderError, StreamEvent};
+
+// A probe that takes longer than this is reported as timed out
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How requests are spread over a model's backends.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    RoundRobin,
+    /// The backend with the fewest requests underway, round-robin among ties.
+    LeastInFlight,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "round_robin" => Ok(Balance::RoundRobin),
+            "least_in_flight" => Ok(Balance::LeastInFlight),
+            other => Err(format!("unknown balancing strategy '{}' (round_robin or least_in_flight)", other)),
+        }
+    }
+}
+
+/// When a backend is ejected: after `failure_threshold` consecutive
+/// failures, for `cooldown`. Once the cool-down is over it gets a single
+/// trial request, other requests still avoiding it meanwhile; succeeding
+/// brings it back, failing ejects it again straight away. Only requests
+/// count; probes and model listings don't.
+///
+/// Ejection fails open: while every backend of a model is ejected, requests
+/// still go to the one due back soonest rather than being refused. With a
+/// single backend the breaker therefore only shows up in `status()` and
+/// gates nothing.
+#[derive(Clone, Copy, Debug)]
+pub struct Breaker {
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+/// How often a request is retried once every backend has failed in a way
+/// that waiting may fix, and how long to wait: `backoff` doubling with each
+/// retry, with jitter, up to `max_delay`. A backend's `Retry-After` is
+/// honored instead, unless it asks for more than `max_delay`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry` (from 0), or `None` to give up.
+    fn delay(&self, retry: u32, error: &ProviderError) -> Option<Duration> {
+        if retry >= self.retries || !worth_waiting(error) {
+            return None;
+        }
+        if let ProviderError::Status { retry_after: Some(after), .. } = error {
+            return (*after <= self.max_delay).then_some(*after);
+        }
+        let ceiling = self.backoff.saturating_mul(1 << retry.min(16)).min(self.max_delay);
+        // Half fixed, half random, so clients failing together don't retry together
+        Some(ceiling / 2 + ceiling.mul_f64(fastrand::f64() / 2.0))
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    ejected_until: Option<Instant>,
+    /// A trial request is underway after the cool-down.
+    trial: bool,
+    last_error: Option<String>,
+}
+
+/// One upstream serving a model.
+pub struct Backend {
+    url: String,
+    provider: Arc<dyn LlmProvider>,
+    in_flight: AtomicUsize,
+    health: Mutex<Health>,
+}
+
+impl Backend {
+    fn ejected_for(&self) -> Option<Duration> {
+        let until = self.health.lock().unwrap().ejected_until?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    /// Whether a request may go to the backend: it isn't ejected, or its
+    /// cool-down is over and nobody has taken the trial yet.
+    fn available(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        health.ejected_until.is_none_or(|until| until <= Instant::now() && !health.trial)
+    }
+
+    /// Lets a request through if the backend is still `available`, taking
+    /// the trial when it is one.
+    fn admit(self: &Arc<Self>) -> Option<Admission> {
+        let mut health = self.health.lock().unwrap();
+        match health.ejected_until {
+            None => Some(Admission(None)),
+            Some(until) if until <= Instant::now() && !health.trial => {
+                health.trial = true;
+                Some(Admission(Some(Arc::clone(self))))
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.ejected_until.take().is_some() {
+            log::info!("Backend {} recovered", redact_url(&self.url));
+        }
+        health.failures = 0;
+    }
+
+    /// Notes why a probe failed, without counting it against the backend.
+    fn probe_failed(&self, error: &ProviderError) {
+        self.health.lock().unwrap().last_error = Some(error.to_string());
+    }
+
+    fn failed(&self, error: &ProviderError, breaker: &Breaker) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        health.last_error = Some(error.to_string());
+        if health.failures >= breaker.failure_threshold {
+            if health.ejected_until.is_none() {
+                log::warn!("Backend {} ejected for {:?} after {} failures: {}", redact_url(&self.url), breaker.cooldown, health.failures, error);
+            }
+            health.ejected_until = Some(Instant::now() + breaker.cooldown);
+        }
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let health = self.health.lock().unwrap();
+        let ejected_for = health.ejected_until.and_then(|until| until.checked_duration_since(Instant::now()));
+        serde_json::json!({
+            "url": redact_url(&self.url),
+            "state": if ejected_for.is_some() { "ejected" } else { "up" },
+            "ejected_for_secs": ejected_for.map(|d| d.as_secs()),
+            "in_flight": self.in_flight.load(Ordering::Relaxed),
+            "consecutive_failures": health.failures,
+            "last_error": health.last_error,
+        })
+    }
+}
+
+/// A request let through to a backend; for a trial, lets the next trial be
+/// taken once dropped, whatever the outcome, and even if the request was
+/// abandoned.
+struct Admission(Option<Arc<Backend>>);
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Some(backend) = &self.0 {
+            backend.health.lock().unwrap().trial = false;
+        }
+    }
+}
+
+/// Counts a request against a backend until dropped.
+struct InFlight(Arc<Backend>);
+
+impl InFlight {
+    fn new(backend: &Arc<Backend>) -> Self {
+        backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(Arc::clone(backend))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Failures that say nothing was generated, so another backend can safely
+/// be asked instead.
+fn retryable(error: &ProviderError) -> bool {
+    match error {
+        ProviderError::Unreachable(_) => true,
+        ProviderError::Status { status, .. } => matches!(status.as_u16(), 429 | 502 | 503 | 504),
+        _ => false,
+    }
+}
+
+/// Failures that may clear up on their own: the backend being unreachable,
+/// rate limiting or temporarily unavailable.
+fn worth_waiting(error: &ProviderError) -> bool {
+    match error {
+        ProviderError::Unreachable(_) => true,
+        ProviderError::Status { status, .. } => matches!(status.as_u16(), 429 | 503),
+        _ => false,
+    }
+}
+
+/// Failures that point at the backend rather than the request.
+fn unhealthy(error: &ProviderError) -> bool {
+    match error {
+        ProviderError::Unreachable(_) | ProviderError::Timeout => true,
+        ProviderError::Status { status, .. } => status.is_server_error(),
+        ProviderError::InvalidResponse(_) => false,
+    }
+}
+
+/// The backends of one model, used as a single provider: each request goes
+/// to a backend picked by `balance`, skipping ejected ones, and moves on to
+/// the next when one fails in a retryable way. When all have, the round is
+/// repeated as `retry` allows.
+pub struct BackendPool {
+    /// Model name the pool's calls are reported under in metrics.
+    model: String,
+    backends: Vec<Arc<Backend>>,
+    balance: Balance,
+    breaker: Breaker,
+    retry: RetryPolicy,
+    next: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl BackendPool {
+    pub fn new(
+        model: String,
+        backends: Vec<(String, Arc<dyn LlmProvider>)>,
+        balance: Balance,
+        breaker: Breaker,
+        retry: RetryPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let backends = backends.into_iter()
+            .map(|(url, provider)| Arc::new(Backend { url, provider, in_flight: AtomicUsize::new(0), health: Mutex::default() }))
+            .collect();
+        BackendPool { model, backends, balance, breaker, retry, next: AtomicUsize::new(0), metrics }
+    }
+
+    /// Backends to try, in order, and whether they are a last resort.
+    /// Ejected ones are skipped, unless none is available, in which case the
+    /// one due back soonest is tried anyway, by every request and without
+    /// waiting for a trial: see `Breaker`.
+    fn candidates(&self) -> (Vec<Arc<Backend>>, bool) {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut up: Vec<Arc<Backend>> = (0..self.backends.len())
+            .map(|i| &self.backends[(start + i) % self.backends.len()])
+            .filter(|b| b.available())
+            .cloned()
+            .collect();
+        if self.balance == Balance::LeastInFlight {
+            up.sort_by_key(|b| b.in_flight.load(Ordering::Relaxed));
+        }
+        if up.is_empty() {
+            let soonest = self.backends.iter().min_by_key(|b| b.ejected_for()).cloned();
+            return (soonest.into_iter().collect(), true);
+        }
+        (up, false)
+    }
+
+    /// Runs `call` against each candidate until one succeeds or fails in a
+    /// way another backend wouldn't fix, backing off and going round again
+    /// when all of them failed. The returned guard keeps the request counted
+    /// as in flight.
+    async fn attempt<'a, T>(
+        &self,
+        call: impl Fn(Arc<dyn LlmProvider>) -> BoxFuture<'a, Result<T, ProviderError>>,
+    ) -> Result<(T, InFlight), ProviderError> {
+        let mut retry = 0;
+        loop {
+            let mut last_error = None;
+            let (candidates, last_resort) = self.candidates();
+            for backend in candidates {
+                // Another request may have taken the trial since
+                let Some(_admission) = backend.admit().or_else(|| last_resort.then_some(Admission(None))) else {
+                    continue;
+                };
+                let guard = InFlight::new(&backend);
+                match call(backend.provider.clone()).await {
+                    Ok(value) => {
+                        backend.succeeded();
+                        return Ok((value, guard));
+                    }
+                    Err(e) => {
+                        if unhealthy(&e) {
+                            backend.failed(&e, &self.breaker);
+                        }
+                        if !retryable(&e) {
+                            return Err(e);
+                        }
+                        log::warn!("Backend {} failed: {}", redact_url(&backend.url), e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+            let error = last_error.unwrap_or_else(|| ProviderError::Unreachable("no backends available".to_string()));
+            let Some(delay) = self.retry.delay(retry, &error) else {
+                return Err(error);
+            };
+            retry += 1;
+            log::info!("Retrying {} in {:?} ({}/{})", self.model, delay, retry, self.retry.retries);
+            actix_web::rt::time::sleep(delay).await;
+        }
+    }
+
+    /// Checks every backend is answering, once and without retries. Ones
+    /// that answer are brought back; failures are only noted for `status()`,
+    /// so a backend slow to list its models isn't ejected while it still
+    /// serves requests.
+    pub async fn probe(&self) {
+        join_all(self.backends.iter().map(|backend| async move {
+            match actix_web::rt::time::timeout(PROBE_TIMEOUT, backend.provider.list_models()).await {
+                Ok(Ok(_)) => backend.succeeded(),
+                Ok(Err(e)) => backend.probe_failed(&e),
+                Err(_) => backend.probe_failed(&ProviderError::Timeout),
+            }
+        }))
+        .await;
+    }
+
+    pub fn status(&self) -> Vec<serde_json::Value> {
+        self.backends.iter().map(|b| b.status()).collect()
+    }
+}
+
+#[async_trait]
+impl LlmProvider for BackendPool {
+    async fn chat(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<Completion, ProviderError> {
+        let started = Instant::now();
+        let (completion, _) = self.attempt(|p| async move { p.chat(messages, params).await }.boxed()).await?;
+        self.metrics.observe_llm(&self.model, "chat", started.elapsed());
+        Ok(completion)
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<EventStream, ProviderError> {
+        // Only a failure to start the stream is retried; once tokens flow the
+        // client has seen them
+        let started = Instant::now();
+        let (stream, guard) = self.attempt(|p| async move { p.chat_stream(messages, params).await }.boxed()).await?;
+        let mut timer = StreamTimer::new(self.metrics.clone(), self.model.clone(), started);
+        Ok(stream.map(move |event| {
+            let _counted = &guard;
+            if let Ok(StreamEvent::Token(_)) = &event {
+                timer.token();
+            }
+            event
+        })
+        .boxed())
+    }
+
+    async fn forward_openai(&self, body: &serde_json::Value) -> Result<Option<reqwest::Response>, ProviderError> {
+        // The response body may still be streaming when this returns, so it
+        // only counts as in flight until the headers arrive
+        let started = Instant::now();
+        let (resp, _) = self.attempt(|p| async move { p.forward_openai(body).await }.boxed()).await?;
+        if resp.is_some() {
+            self.metrics.observe_llm(&self.model, "forward", started.elapsed());
+        }
+        Ok(resp)
+    }
+
+    /// The first answer from the backends, available ones asked first.
+    /// Like `probe` this neither retries nor counts failures, as it backs
+    /// readiness checks and model listings rather than requests.
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let mut backends: Vec<&Arc<Backend>> = self.backends.iter().collect();
+        backends.sort_by_key(|b| !b.available());
+        let mut last_error = ProviderError::Unreachable("no backends available".to_string());
+        for backend in backends {
+            match backend.provider.list_models().await {
+                Ok(ids) => return Ok(ids),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        retries: 3,
+        backoff: Duration::from_millis(100),
+        max_delay: Duration::from_millis(300),
+    };
+
+    fn status(code: u16, retry_after: Option<Duration>) -> ProviderError {
+        ProviderError::Status { status: StatusCode::from_u16(code).unwrap(), message: String::new(), retry_after }
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter_up_to_the_cap() {
+        let unreachable = ProviderError::Unreachable("refused".to_string());
+        for _ in 0..50 {
+            let first = POLICY.delay(0, &unreachable).unwrap();
+            assert!((Duration::from_millis(50)..=Duration::from_millis(100)).contains(&first), "{:?}", first);
+            let second = POLICY.delay(1, &unreachable).unwrap();
+            assert!((Duration::from_millis(100)..=Duration::from_millis(200)).contains(&second), "{:?}", second);
+            let third = POLICY.delay(2, &unreachable).unwrap();
+            assert!((Duration::from_millis(150)..=Duration::from_millis(300)).contains(&third), "{:?}", third);
+        }
+        assert!(POLICY.delay(3, &unreachable).is_none());
+    }
+
+    #[test]
+    fn only_waits_for_failures_that_may_clear_up() {
+        assert!(POLICY.delay(0, &status(503, None)).is_some());
+        assert!(POLICY.delay(0, &status(429, None)).is_some());
+        assert!(POLICY.delay(0, &status(502, None)).is_none());
+        assert!(POLICY.delay(0, &status(400, None)).is_none());
+        assert!(POLICY.delay(0, &ProviderError::Timeout).is_none());
+    }
+
+    #[test]
+    fn honors_retry_after_up_to_max_delay() {
+        let soon = status(429, Some(Duration::from_millis(250)));
+        assert_eq!(POLICY.delay(0, &soon), Some(Duration::from_millis(250)));
+        let later = status(429, Some(Duration::from_secs(30)));
+        assert_eq!(POLICY.delay(0, &later), None);
+        // Still bounded by the number of retries
+        assert_eq!(POLICY.delay(3, &soon), None);
+    }
+
+    /// A backend whose every call fails as unreachable.
+    struct Down;
+
+    #[async_trait]
+    impl LlmProvider for Down {
+        async fn chat(&self, _: &[ChatMessage], _: &GenerationParams) -> Result<Completion, ProviderError> {
+            Err(ProviderError::Unreachable("refused".to_string()))
+        }
+
+        async fn chat_stream(&self, _: &[ChatMessage], _: &GenerationParams) -> Result<EventStream, ProviderError> {
+            Err(ProviderError::Unreachable("refused".to_string()))
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+            Err(ProviderError::Unreachable("refused".to_string()))
+        }
+    }
+
+    fn pool(backends: usize) -> BackendPool {
+        let backends = (0..backends).map(|i| (format!("http://b{}", i), Arc::new(Down) as Arc<dyn LlmProvider>)).collect();
+        let breaker = Breaker { failure_threshold: 1, cooldown: Duration::from_secs(60) };
+        let retry = RetryPolicy { retries: 0, ..POLICY };
+        BackendPool::new("m".to_string(), backends, Balance::RoundRobin, breaker, retry, Arc::new(Metrics::new()))
+    }
+
+    #[actix_web::test]
+    async fn probes_and_model_listings_never_eject() {
+        let pool = pool(2);
+        for _ in 0..3 {
+            pool.probe().await;
+            assert!(pool.list_models().await.is_err());
+        }
+        assert!(pool.backends.iter().all(|b| b.available()));
+        assert_eq!(pool.status()[0]["last_error"], "request failed: refused");
+        assert_eq!(pool.status()[0]["consecutive_failures"], 0);
+    }
+
+    #[actix_web::test]
+    async fn failed_requests_eject_but_the_last_backend_stays_reachable() {
+        let pool = pool(1);
+        assert!(pool.chat(&[], &GenerationParams::default()).await.is_err());
+        assert!(!pool.backends[0].available());
+        // Failing open: the ejected backend is still tried rather than refusing outright
+        let (candidates, last_resort) = pool.candidates();
+        assert_eq!((candidates.len(), last_resort), (1, true));
+    }
+
+    #[test]
+    fn parses_balancing_strategies() {
+        assert_eq!("round-robin".parse::<Balance>(), Ok(Balance::RoundRobin));
+        assert_eq!("Least_In_Flight".parse::<Balance>(), Ok(Balance::LeastInFlight));
+        assert!("random".parse::<Balance>().is_err());
+    }
+}
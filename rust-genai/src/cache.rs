@@ -1,32 +1,378 @@
-use dashmap::DashMap;
-use std::time::{Duration, Instant};
-use std::sync::Arc;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::conversation::ChatMessage;
+use crate::params::GenerationParams;
 
+/// Response header telling clients whether an answer came from the cache.
+pub const X_CACHE: &str = "X-Cache";
+
+/// Per-request cache control.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Serve from the cache when possible and store fresh answers.
+    #[default]
+    Use,
+    /// Skip the cache entirely.
+    Bypass,
+    /// Always ask the model, then overwrite the cached answer.
+    Refresh,
+}
+
+impl CacheMode {
+    pub fn reads(self) -> bool {
+        self == CacheMode::Use
+    }
+
+    pub fn writes(self) -> bool {
+        self != CacheMode::Bypass
+    }
+}
+
+/// Derives the cache key for an upstream request: a SHA-256 over the model,
+/// generation settings and the exact message list sent (system prompt and
+/// trimmed history included), so changing any of them never serves a stale
+/// answer.
+pub fn request_key(model: &str, messages: &[ChatMessage], params: &GenerationParams) -> String {
+    let normalized = serde_json::json!({
+        "model": model,
+        "params": params,
+        "messages": messages.iter()
+            .map(|m| serde_json::json!({"role": m.role, "content": m.content.trim()}))
+            .collect::<Vec<_>>(),
+    });
+    format!("{:x}", Sha256::digest(normalized.to_string().as_bytes()))
+}
+
+/// Cache key for a raw API request body. `serde_json` keeps object keys
+/// sorted, so field order in the client's JSON doesn't matter.
+pub fn body_key(body: &serde_json::Value) -> String {
+    format!("{:x}", Sha256::digest(body.to_string().as_bytes()))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    value: String,
+    // Unix seconds rather than `Instant` so entries keep their age across restarts
+    stored_at: u64,
+}
+
+impl Entry {
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.value.len()
+    }
+}
+
+struct Inner {
+    entries: LruCache<String, Entry>,
+    bytes: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    pub persistent: bool,
+}
+
+/// Response cache bounded by entry count and total key+value bytes, evicting
+/// least recently used entries first. With a disk store attached, writes go
+/// through to sled and surviving entries are reloaded on startup.
 pub struct AppCache {
-    map: DashMap<String, (String, Instant)>,
+    inner: Mutex<Inner>,
     ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    disk: Option<sled::Db>,
+    counters: Counters,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 impl AppCache {
-    pub fn new() -> Self {
+    pub fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
         AppCache {
-            map: DashMap::new(),
-            ttl: Duration::from_secs(300), // 5 minutes
+            inner: Mutex::new(Inner { entries: LruCache::unbounded(), bytes: 0 }),
+            ttl,
+            max_entries,
+            max_bytes,
+            disk: None,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Persists entries in a sled database at `path`, loading whatever is
+    /// still fresh from a previous run.
+    pub fn with_disk(mut self, path: &str) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        let mut stale = Vec::new();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for item in db.iter() {
+                let (key, raw) = item?;
+                let parsed = std::str::from_utf8(&key).ok()
+                    .zip(serde_json::from_slice::<Entry>(&raw).ok());
+                match parsed {
+                    Some((k, entry)) if !self.is_expired(&entry) => {
+                        inner.bytes += entry.size(k);
+                        inner.entries.put(k.to_string(), entry);
+                    }
+                    _ => stale.push(key),
+                }
+            }
+            self.evict_over_limits(&mut inner);
+        }
+        for key in stale {
+            db.remove(key)?;
         }
+        log::info!("Loaded {} cached responses from {}", self.len(), path);
+        self.disk = Some(db);
+        Ok(self)
     }
 
+    fn is_expired(&self, entry: &Entry) -> bool {
+        now_secs().saturating_sub(entry.stored_at) >= self.ttl.as_secs()
+    }
+
+    #[tracing::instrument(name = "cache.get", skip_all, fields(hit = false))]
     pub fn get(&self, key: &str) -> Option<String> {
-        if let Some((val, ts)) = self.map.get(key).map(|v| v.value().clone()) {
-            if ts.elapsed() < self.ttl {
-                return Some(val);
-            } else {
-                self.map.remove(key);
+        let mut inner = self.inner.lock().unwrap();
+        let expired = match inner.entries.get(key) {
+            Some(entry) if !self.is_expired(entry) => {
+                tracing::Span::current().record("hit", true);
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.value.clone());
             }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.remove_entry(&mut inner, key);
+            self.counters.expirations.fetch_add(1, Ordering::Relaxed);
         }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
         None
     }
 
     pub fn set(&self, key: String, value: String) {
-        self.map.insert(key, (value, Instant::now()));
+        let entry = Entry { value, stored_at: now_secs() };
+        let size = entry.size(&key);
+        if size > self.max_bytes {
+            return;
+        }
+        if let Some(db) = &self.disk {
+            if let Ok(raw) = serde_json::to_vec(&entry) {
+                if let Err(e) = db.insert(key.as_bytes(), raw) {
+                    log::warn!("Failed to persist cache entry: {}", e);
+                }
+            }
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.entries.put(key.clone(), entry) {
+            inner.bytes -= old.size(&key);
+        }
+        inner.bytes += size;
+        self.evict_over_limits(&mut inner);
+    }
+
+    fn evict_over_limits(&self, inner: &mut Inner) {
+        while inner.entries.len() > self.max_entries || inner.bytes > self.max_bytes {
+            let Some((key, entry)) = inner.entries.pop_lru() else { break };
+            inner.bytes -= entry.size(&key);
+            self.forget_on_disk(&key);
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove_entry(&self, inner: &mut Inner, key: &str) {
+        if let Some(entry) = inner.entries.pop(key) {
+            inner.bytes -= entry.size(key);
+        }
+        self.forget_on_disk(key);
+    }
+
+    fn forget_on_disk(&self, key: &str) {
+        if let Some(db) = &self.disk {
+            if let Err(e) = db.remove(key.as_bytes()) {
+                log::warn!("Failed to remove persisted cache entry: {}", e);
+            }
+        }
+    }
+
+    /// Drops every expired entry. `get` only notices expiry for keys that are
+    /// asked for again, so this runs periodically to reclaim the rest.
+    pub fn purge_expired(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<String> = inner.entries.iter()
+            .filter(|(_, entry)| self.is_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_entry(&mut inner, key);
+        }
+        self.counters.expirations.fetch_add(expired.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Runs `purge_expired` every `every` on the current runtime.
+    pub fn spawn_sweeper(self: &Arc<Self>, every: Duration) {
+        let cache = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                cache.purge_expired();
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+            persistent: self.disk.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn value(n: usize) -> String {
+        "x".repeat(n)
+    }
+
+    #[test]
+    fn evicts_least_recently_used_over_the_entry_limit() {
+        let cache = AppCache::new(HOUR, 2, 1024);
+        cache.set("a".to_string(), value(1));
+        cache.set("b".to_string(), value(1));
+        // Reading `a` leaves `b` as the eviction candidate
+        assert!(cache.get("a").is_some());
+        cache.set("c".to_string(), value(1));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn evicts_over_the_byte_limit() {
+        let cache = AppCache::new(HOUR, 100, 25);
+        cache.set("a".to_string(), value(9));
+        cache.set("b".to_string(), value(9));
+        assert_eq!(cache.stats().bytes, 20);
+        cache.set("c".to_string(), value(9));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 20, 1));
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn skips_values_larger_than_the_whole_cache() {
+        let cache = AppCache::new(HOUR, 100, 10);
+        cache.set("a".to_string(), value(5));
+        cache.set("b".to_string(), value(50));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+    }
+
+    #[test]
+    fn overwriting_replaces_the_size() {
+        let cache = AppCache::new(HOUR, 100, 1024);
+        cache.set("a".to_string(), value(100));
+        cache.set("a".to_string(), value(10));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 11));
+    }
+
+    #[test]
+    fn expired_entries_miss_and_are_purged() {
+        let cache = AppCache::new(Duration::ZERO, 100, 1024);
+        cache.set("a".to_string(), value(1));
+        cache.set("b".to_string(), value(1));
+        assert!(cache.get("a").is_none());
+        cache.purge_expired();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.expirations, stats.misses), (0, 0, 2, 1));
+    }
+
+    #[test]
+    fn reloads_from_disk_within_the_limits() {
+        let dir = std::env::temp_dir().join(format!("genai-cache-{}", uuid::Uuid::new_v4()));
+        let path = dir.to_str().unwrap();
+        {
+            let cache = AppCache::new(HOUR, 100, 1024).with_disk(path).unwrap();
+            for key in ["a", "b", "c"] {
+                cache.set(key.to_string(), value(1));
+            }
+        }
+        // sled lets go of its lock file from a background thread, so the
+        // reopen may briefly find it still held
+        let cache = (0..50)
+            .find_map(|_| AppCache::new(HOUR, 2, 1024).with_disk(path).ok().or_else(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                None
+            }))
+            .expect("reopening the cache store");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.stats().persistent);
+        drop(cache);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn keys_change_with_model_messages_and_settings() {
+        let messages = vec![ChatMessage::new("user", "hi")];
+        let params = GenerationParams::default();
+        let key = request_key("m", &messages, &params);
+        assert_eq!(key, request_key("m", &[ChatMessage::new("user", " hi ")], &params));
+        assert_ne!(key, request_key("other", &messages, &params));
+        assert_ne!(key, request_key("m", &[ChatMessage::new("user", "hello")], &params));
+        let warmer = GenerationParams { temperature: Some(0.9), ..GenerationParams::default() };
+        assert_ne!(key, request_key("m", &messages, &warmer));
+    }
+
+    #[test]
+    fn cache_modes() {
+        assert!(CacheMode::Use.reads() && CacheMode::Use.writes());
+        assert!(!CacheMode::Refresh.reads() && CacheMode::Refresh.writes());
+        assert!(!CacheMode::Bypass.reads() && !CacheMode::Bypass.writes());
     }
 }
@@ -0,0 +1,220 @@
+use actix_web::http::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Tags a browser when auth is off; see `ClientId::for_browser`.
+pub const BROWSER_COOKIE: &str = "genai_browser";
+
+/// An IP network in CIDR notation; a bare address is treated as a /32 or /128.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid IP address in '{}'", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<IpNet> for String {
+    fn from(net: IpNet) -> Self {
+        net.to_string()
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Resolves the real client address. `X-Forwarded-For` is only honoured when
+/// the direct peer is a trusted proxy, and then the chain is walked from the
+/// right, skipping further trusted hops, so a client can't spoof its address
+/// by sending the header itself.
+pub fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> String {
+    let Some(peer) = peer.map(|p| p.ip()) else {
+        return "unknown".to_string();
+    };
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !is_trusted(peer) {
+        return peer.to_string();
+    }
+    let forwarded: Vec<IpAddr> = headers.get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    forwarded.iter().rev()
+        .find(|ip| !is_trusted(**ip))
+        .or(forwarded.first())
+        .unwrap_or(&peer)
+        .to_string()
+}
+
+/// Who a request is accounted to: an API key or web UI session (stored
+/// hashed, so raw secrets don't sit in limiter state) or, failing that, the
+/// client IP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientId(String);
+
+impl ClientId {
+    pub fn for_key(key: &str) -> Self {
+        Self::for_key_hash(&format!("{:x}", Sha256::digest(key.as_bytes())))
+    }
+
+    /// For keys only known by their hex SHA-256, as in the API key store.
+    pub fn for_key_hash(hash: &str) -> Self {
+        ClientId(format!("key:{}", hash.to_ascii_lowercase()))
+    }
+
+    /// A web UI session, so browsers behind one NAT aren't lumped together.
+    pub fn for_session(token: &str) -> Self {
+        ClientId(format!("session:{:x}", Sha256::digest(token.as_bytes())))
+    }
+
+    /// A browser, by the cookie `/` sets when auth is off. The id is chosen
+    /// client-side in effect, so it only keeps conversations apart; limits
+    /// and quotas stay per IP.
+    pub fn for_browser(id: &str) -> Self {
+        ClientId(format!("browser:{:x}", Sha256::digest(id.as_bytes())))
+    }
+
+    pub fn for_ip(ip: &str) -> Self {
+        ClientId(format!("ip:{}", ip))
+    }
+
+    pub fn is_ip(&self) -> bool {
+        self.0.starts_with("ip:")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Client-supplied API key, from `Authorization: Bearer` or `X-API-Key`.
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers.get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(HeaderName::from_static("x-forwarded-for"), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    #[test]
+    fn parses_networks() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert_eq!("192.168.1.1".parse::<IpNet>().unwrap().to_string(), "192.168.1.1/32");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("not-an-ip".parse::<IpNet>().is_err());
+        assert!("::/0".parse::<IpNet>().unwrap().contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let headers = forwarded(&["1.2.3.4"]);
+        assert_eq!(client_ip(peer("203.0.113.9"), &headers, &[]), "203.0.113.9");
+        assert_eq!(client_ip(peer("203.0.113.9"), &headers, &nets(&["10.0.0.0/8"])), "203.0.113.9");
+    }
+
+    #[test]
+    fn walks_forwarded_for_from_the_right_past_trusted_hops() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        // The client claims 6.6.6.6, but the first hop the proxies saw is 1.2.3.4
+        let headers = forwarded(&["6.6.6.6, 1.2.3.4", "10.0.0.2"]);
+        assert_eq!(client_ip(peer("10.0.0.1"), &headers, &trusted), "1.2.3.4");
+    }
+
+    #[test]
+    fn falls_back_when_every_hop_is_trusted_or_missing() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        assert_eq!(client_ip(peer("10.0.0.1"), &forwarded(&["10.0.0.5, 10.0.0.6"]), &trusted), "10.0.0.5");
+        assert_eq!(client_ip(peer("10.0.0.1"), &forwarded(&["garbage"]), &trusted), "10.0.0.1");
+        assert_eq!(client_ip(peer("10.0.0.1"), &HeaderMap::new(), &trusted), "10.0.0.1");
+        assert_eq!(client_ip(None, &HeaderMap::new(), &trusted), "unknown");
+    }
+
+    #[test]
+    fn hashes_keys_and_reads_them_from_headers() {
+        assert_eq!(ClientId::for_key("abc"), ClientId::for_key_hash(&crate::auth::hash_key("abc").to_ascii_uppercase()));
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("x-api-key"), HeaderValue::from_static("k1"));
+        assert_eq!(api_key(&headers), Some("k1"));
+        headers.insert(HeaderName::from_static("authorization"), HeaderValue::from_static("Bearer k2"));
+        assert_eq!(api_key(&headers), Some("k2"));
+    }
+
+    #[test]
+    fn keeps_sessions_apart_from_keys_and_each_other() {
+        let session = ClientId::for_session("t1");
+        assert!(session.as_str().starts_with("session:"));
+        assert!(!session.as_str().contains("t1"));
+        assert_ne!(session, ClientId::for_session("t2"));
+        assert_ne!(session.as_str(), ClientId::for_key("t1").as_str());
+    }
+}
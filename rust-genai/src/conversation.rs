@@ -0,0 +1,293 @@
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::client::ClientId;
+use crate::error::ApiError;
+
+// Rough chars-per-token ratio for English text; good enough to stay under the window
+const CHARS_PER_TOKEN: usize = 4;
+// Per-message overhead for role markers in the chat template
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+const TITLE_MAX_CHARS: usize = 50;
+
+/// Longest message a client may send, in bytes.
+pub const MAX_MESSAGE_LEN: usize = 4000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        ChatMessage { role: role.to_string(), content: content.into() }
+    }
+
+    fn estimated_tokens(&self) -> usize {
+        estimate_text_tokens(&self.content) + MESSAGE_OVERHEAD_TOKENS
+    }
+}
+
+/// Rough token count for text, for when the upstream doesn't report usage.
+pub fn estimate_text_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Rough prompt size of a message list, chat-template overhead included.
+pub fn estimate_tokens(messages: &[ChatMessage]) -> usize {
+    messages.iter().map(ChatMessage::estimated_tokens).sum()
+}
+
+#[derive(Clone, Serialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub messages: Vec<ChatMessage>,
+    /// The client that started it; nobody else can see or continue it.
+    #[serde(skip)]
+    owner: ClientId,
+}
+
+#[derive(Serialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub title: String,
+    pub message_count: usize,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Conversations kept in memory, bounded by count: the least recently used
+/// one is dropped to make room, and ones left idle for `idle_ttl` expire.
+/// Each belongs to the client that created it, and to everyone else it
+/// looks as if it doesn't exist.
+pub struct ConversationStore {
+    inner: Mutex<LruCache<String, Conversation>>,
+    max_entries: usize,
+    idle_ttl: Duration,
+}
+
+impl ConversationStore {
+    pub fn new(max_entries: usize, idle_ttl: Duration) -> Self {
+        ConversationStore { inner: Mutex::new(LruCache::unbounded()), max_entries, idle_ttl }
+    }
+
+    fn is_expired(&self, conversation: &Conversation, now: DateTime<Utc>) -> bool {
+        now.signed_duration_since(conversation.updated_at).to_std().is_ok_and(|idle| idle >= self.idle_ttl)
+    }
+
+    /// Looks up a live conversation of `owner`, marking it recently used
+    /// and dropping it if it has expired.
+    fn with<T>(&self, id: &str, owner: &ClientId, f: impl FnOnce(&mut Conversation) -> T) -> Option<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let conversation = inner.peek(id).filter(|c| c.owner == *owner)?;
+        if self.is_expired(conversation, Utc::now()) {
+            inner.pop(id);
+            return None;
+        }
+        inner.get_mut(id).map(f)
+    }
+
+    /// Creates an empty conversation titled after its first message.
+    pub fn create(&self, owner: &ClientId, first_message: &str) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let title: String = first_message.chars().take(TITLE_MAX_CHARS).collect();
+        let mut inner = self.inner.lock().unwrap();
+        while inner.len() >= self.max_entries && inner.pop_lru().is_some() {}
+        inner.put(id.clone(), Conversation {
+            id: id.clone(),
+            title,
+            created_at: now,
+            updated_at: now,
+            messages: Vec::new(),
+            owner: owner.clone(),
+        });
+        id
+    }
+
+    pub fn get(&self, id: &str, owner: &ClientId) -> Option<Conversation> {
+        self.with(id, owner, |c| c.clone())
+    }
+
+    pub fn history(&self, id: &str, owner: &ClientId) -> Option<Vec<ChatMessage>> {
+        self.with(id, owner, |c| c.messages.clone())
+    }
+
+    /// Records a completed user/assistant exchange.
+    pub fn append_turn(&self, id: &str, owner: &ClientId, user: String, assistant: String) {
+        self.with(id, owner, |c| {
+            c.messages.push(ChatMessage::new("user", user));
+            c.messages.push(ChatMessage::new("assistant", assistant));
+            c.updated_at = Utc::now();
+        });
+    }
+
+    pub fn list(&self, owner: &ClientId) -> Vec<ConversationSummary> {
+        let now = Utc::now();
+        let inner = self.inner.lock().unwrap();
+        let mut list: Vec<ConversationSummary> = inner.iter()
+            .map(|(_, c)| c)
+            .filter(|c| c.owner == *owner && !self.is_expired(c, now))
+            .map(|c| ConversationSummary {
+                id: c.id.clone(),
+                title: c.title.clone(),
+                message_count: c.messages.len(),
+                created_at: c.created_at,
+                updated_at: c.updated_at,
+            })
+            .collect();
+        list.sort_by_key(|c| std::cmp::Reverse(c.updated_at));
+        list
+    }
+
+    pub fn rename(&self, id: &str, owner: &ClientId, title: String) -> bool {
+        self.with(id, owner, |c| {
+            c.title = title;
+            c.updated_at = Utc::now();
+        })
+        .is_some()
+    }
+
+    pub fn delete(&self, id: &str, owner: &ClientId) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.peek(id).is_none_or(|c| c.owner != *owner) {
+            return false;
+        }
+        inner.pop(id).is_some()
+    }
+
+    /// Drops every expired conversation. Lookups only notice expiry for
+    /// conversations that are asked for again, so this runs periodically.
+    pub fn purge_expired(&self) {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<String> = inner.iter()
+            .filter(|(_, c)| self.is_expired(c, now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            inner.pop(id);
+        }
+    }
+
+    /// Runs `purge_expired` every `every` on the current runtime.
+    pub fn spawn_sweeper(self: &Arc<Self>, every: Duration) {
+        let store = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                store.purge_expired();
+            }
+        });
+    }
+}
+
+/// Builds the upstream message list: system prompt, as much recent history as
+/// fits, and the new user message. `budget` is the number of prompt tokens
+/// available, i.e. the model's context size minus room for the reply.
+/// Oldest turns are dropped first, a user/assistant pair at a time. Fails
+/// when the system prompt and message alone don't fit.
+pub fn build_context(system_prompt: &str, history: &[ChatMessage], message: &str, budget: usize) -> Result<Vec<ChatMessage>, ApiError> {
+    let system = ChatMessage::new("system", system_prompt);
+    let user = ChatMessage::new("user", message);
+    let mut used = system.estimated_tokens() + user.estimated_tokens();
+    if used > budget {
+        return Err(ApiError::InvalidRequest(format!(
+            "Message and system prompt take about {} tokens, more than the {} the model has room for",
+            used, budget
+        )));
+    }
+
+    let mut kept = 0;
+    for pair in history.rchunks(2) {
+        let cost: usize = pair.iter().map(ChatMessage::estimated_tokens).sum();
+        if used + cost > budget {
+            break;
+        }
+        used += cost;
+        kept += pair.len();
+    }
+
+    let mut messages = Vec::with_capacity(kept + 2);
+    messages.push(system);
+    messages.extend_from_slice(&history[history.len() - kept..]);
+    messages.push(user);
+    Ok(messages)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four characters, so every message below costs 1 + 4 overhead tokens
+    fn turns(n: usize) -> Vec<ChatMessage> {
+        (0..n).flat_map(|i| [ChatMessage::new("user", format!("q{:03}", i)), ChatMessage::new("assistant", format!("a{:03}", i))]).collect()
+    }
+
+    #[test]
+    fn keeps_all_history_that_fits() {
+        let history = turns(2);
+        let messages = build_context("sys!", &history, "next", 100).unwrap();
+        assert_eq!(messages.len(), 6);
+        assert_eq!(messages[0].role, "system");
+        assert_eq!(messages[1].content, "q000");
+        assert_eq!(messages[5].content, "next");
+    }
+
+    #[test]
+    fn drops_the_oldest_pairs_first() {
+        let history = turns(3);
+        // System and message take 10, leaving room for two pairs but not three
+        let messages = build_context("sys!", &history, "next", 10 + 20 + 9).unwrap();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["sys!", "q001", "a001", "q002", "a002", "next"]);
+        // A pair is kept or dropped whole
+        let messages = build_context("sys!", &history, "next", 10 + 5).unwrap();
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn rejects_a_prompt_that_alone_exceeds_the_budget() {
+        assert!(build_context("sys!", &[], "next", 10).is_ok());
+        let err = build_context("sys!", &turns(1), "next", 9).unwrap_err();
+        assert!(matches!(err, ApiError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[test]
+    fn hides_conversations_from_other_clients() {
+        let store = ConversationStore::new(10, Duration::from_secs(60));
+        let alice = ClientId::for_browser("alice");
+        let bob = ClientId::for_browser("bob");
+        let id = store.create(&alice, "hello");
+        store.append_turn(&id, &alice, "hello".to_string(), "hi".to_string());
+        // Bob's writes to Alice's conversation go nowhere
+        store.append_turn(&id, &bob, "psst".to_string(), "no".to_string());
+        assert_eq!(store.history(&id, &alice).map(|h| h.len()), Some(2));
+        assert!(store.get(&id, &bob).is_none());
+        assert!(store.list(&bob).is_empty());
+        assert!(!store.rename(&id, &bob, "mine".to_string()));
+        assert!(!store.delete(&id, &bob));
+        assert_eq!(store.list(&alice).len(), 1);
+        assert!(store.delete(&id, &alice));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_conversation() {
+        let store = ConversationStore::new(2, Duration::from_secs(60));
+        let owner = ClientId::for_ip("10.0.0.1");
+        let first = store.create(&owner, "one");
+        let second = store.create(&owner, "two");
+        store.get(&first, &owner);
+        store.create(&owner, "three");
+        assert!(store.get(&first, &owner).is_some());
+        assert!(store.get(&second, &owner).is_none());
+    }
+}
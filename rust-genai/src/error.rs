@@ -0,0 +1,126 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use crate::providers::ProviderError;
+use crate::quota::QuotaExceeded;
+use crate::rate_limit::Decision;
+
+/// Errors returned by the JSON API. Every variant maps to an HTTP status and a
+/// stable machine-readable `code` so clients don't have to parse messages.
+#[derive(Debug)]
+pub enum ApiError {
+    RateLimited(Decision),
+    QuotaExceeded(QuotaExceeded),
+    InvalidRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(&'static str),
+    Upstream(ProviderError),
+}
+
+impl ApiError {
+    fn classify(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            ApiError::QuotaExceeded(_) => (StatusCode::TOO_MANY_REQUESTS, "quota_exceeded"),
+            ApiError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, "invalid_request"),
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ApiError::Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::Upstream(e) => match e {
+                ProviderError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout"),
+                ProviderError::Unreachable(_) => (StatusCode::SERVICE_UNAVAILABLE, "upstream_unavailable"),
+                ProviderError::Status { status, .. } if status.as_u16() == 429 => {
+                    (StatusCode::SERVICE_UNAVAILABLE, "upstream_busy")
+                }
+                ProviderError::Status { .. } if e.is_context_length_exceeded() => {
+                    (StatusCode::UNPROCESSABLE_ENTITY, "context_length_exceeded")
+                }
+                ProviderError::Status { status, .. } if status.is_client_error() => {
+                    (StatusCode::BAD_REQUEST, "upstream_rejected")
+                }
+                ProviderError::Status { .. } => (StatusCode::BAD_GATEWAY, "upstream_error"),
+                ProviderError::InvalidResponse(_) => (StatusCode::BAD_GATEWAY, "upstream_invalid_response"),
+            },
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.classify().1
+    }
+
+    /// JSON body shared by plain responses and SSE `error` events.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        });
+        if let ApiError::Upstream(ProviderError::Status { status, .. }) = self {
+            body["upstream_status"] = status.as_u16().into();
+        }
+        if let ApiError::QuotaExceeded(quota) = self {
+            body["quota"] = serde_json::json!(quota);
+        }
+        body
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::RateLimited(_) => write!(f, "Rate limit exceeded"),
+            ApiError::QuotaExceeded(q) => write!(f, "{} token quota exceeded", q.period),
+            ApiError::InvalidRequest(msg) => write!(f, "{}", msg),
+            ApiError::Unauthorized => write!(f, "Missing or invalid API key"),
+            ApiError::Forbidden(msg) => write!(f, "{}", msg),
+            ApiError::NotFound(what) => write!(f, "{} not found", what),
+            ApiError::Upstream(e) => match self.code() {
+                "upstream_timeout" => write!(f, "LLM API timed out"),
+                "upstream_unavailable" => write!(f, "LLM API is unreachable"),
+                "upstream_busy" => write!(f, "LLM API is busy, try again later"),
+                "context_length_exceeded" => write!(f, "Conversation is too long for the model's context window"),
+                "upstream_rejected" => write!(f, "LLM API rejected the request: {}", e.upstream_message()),
+                "upstream_invalid_response" => write!(f, "LLM API returned an unreadable response"),
+                _ => write!(f, "LLM API error"),
+            },
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.classify().0
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code()).json(self.to_json());
+        self.apply_headers(resp.headers_mut());
+        resp
+    }
+}
+
+impl ApiError {
+    /// Headers telling clients when to retry or how to authenticate.
+    pub fn apply_headers(&self, headers: &mut header::HeaderMap) {
+        match self {
+            ApiError::RateLimited(decision) => decision.apply_headers(headers),
+            ApiError::QuotaExceeded(quota) => {
+                headers.insert(header::RETRY_AFTER, header::HeaderValue::from(quota.retry_after));
+            }
+            // Passed on when the backend stayed busy through our retries
+            ApiError::Upstream(ProviderError::Status { retry_after: Some(delay), .. }) => {
+                headers.insert(header::RETRY_AFTER, header::HeaderValue::from(delay.as_secs().max(1)));
+            }
+            ApiError::Unauthorized => {
+                headers.insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl From<ProviderError> for ApiError {
+    fn from(e: ProviderError) -> Self {
+        log::warn!("LLM call failed: {}", e);
+        ApiError::Upstream(e)
+    }
+}
@@ -1,168 +1,572 @@
-use actix_web::{get, post, web, HttpResponse, Responder, HttpRequest};
-use tera::{Tera, Context};
-use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{delete, get, patch, post, routes, web, HttpRequest, HttpResponse, Responder};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::web::Bytes;
 use serde::{Deserialize, Serialize};
+use futures::StreamExt;
+use tracing::Instrument;
+use crate::assets::StaticFiles;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::auth::{self, KeyStore, Principal};
 use crate::config::AppConfig;
-use crate::cache::AppCache;
+use crate::cache::{self, AppCache, CacheMode};
+use crate::client::{self, ClientId};
+use crate::conversation::{self, ChatMessage, ConversationStore};
+use crate::error::ApiError;
+use crate::health::HealthState;
+use crate::metrics::Metrics;
+use crate::params::GenerationParams;
+use crate::models::{Model, ModelRegistry};
+use crate::persona::{Persona, PersonaStore};
+use crate::providers::{LlmProvider, StreamEvent, Usage};
+use crate::quota::QuotaTracker;
 use crate::rate_limit::RateLimiter;
+use crate::redact::Redactor;
+use crate::sse;
+use crate::telemetry;
+use crate::templates::{self, Templates};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::fs;
+use std::time::Instant;
+
+// Tokens of the context window kept free for the model's reply
+const RESPONSE_RESERVE_TOKENS: usize = 512;
 
 #[get("/")]
-pub async fn index() -> impl Responder {
-    // Initialize Tera templating engine for templates directory
-    let tera = match tera::Tera::new("templates/**/*") {
-        Ok(t) => t,
+pub async fn index(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    models: web::Data<Arc<ModelRegistry>>,
+    templates: web::Data<Arc<Templates>>,
+    keys: web::Data<Arc<KeyStore>>,
+    rate_limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    let default_model = models.default_model();
+    let mut context = tera::Context::new();
+    context.insert("llm_model", &default_model.spec.name);
+    context.insert("llm_base_url", &default_model.spec.backends.first());
+    context.insert("default_persona", &config.default_persona);
+
+    // Render the index template with context
+    let rendered = match templates.render("index.html", &context) {
+        Ok(html) => html,
+        Err(e) if config.dev_mode => {
+            return HttpResponse::InternalServerError().content_type("text/html").body(templates::error_page(&e));
+        }
         Err(e) => {
-            eprintln!("Template parsing error: {}", e);
-            std::process::exit(1);
+            log::error!("Rendering index.html failed: {}", e);
+            "<h1>Hello GenAI</h1>".to_string()
         }
     };
+    let mut resp = HttpResponse::Ok();
+    resp.content_type("text/html");
+    if let Some(cookie) = session_cookie(&req, &config, &keys, &rate_limiter).or_else(|| browser_cookie(&req, &keys)) {
+        resp.cookie(cookie);
+    }
+    resp.body(rendered)
+}
 
-    // Load configuration from environment
-    let config = crate::config::AppConfig::from_env();
-    let mut context = tera::Context::new();
-    context.insert("llm_model", &config.llm_model_name);
-    context.insert("llm_base_url", &config.llm_base_url);
+/// Hands the web UI a session when API keys are enforced, so the page can
+/// call the API without embedding a key. Existing sessions are kept; new
+/// ones are rate-limited per client IP under the `sessions` route.
+fn session_cookie(req: &HttpRequest, config: &AppConfig, keys: &KeyStore, rate_limiter: &RateLimiter) -> Option<Cookie<'static>> {
+    if !keys.enabled() {
+        return None;
+    }
+    if req.cookie(auth::SESSION_COOKIE).is_some_and(|c| keys.authenticate_session(c.value()).is_some()) {
+        return None;
+    }
+    let ip = client::client_ip(req.peer_addr(), req.headers(), &config.trusted_proxies);
+    if !rate_limiter.check("sessions", &ClientId::for_ip(&ip)).allowed {
+        log::warn!("Not starting a web UI session for {}: rate limited", ip);
+        return None;
+    }
+    let Some(token) = keys.create_session() else {
+        log::warn!("Not starting a web UI session: session limit reached");
+        return None;
+    };
+    let ttl = keys.session_ttl().as_secs() as i64;
+    Some(Cookie::build(auth::SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(actix_web::cookie::time::Duration::seconds(ttl))
+        .finish())
+}
 
-    // Render the index template with context
-    let rendered = tera.render("index.html", &context)
-        .unwrap_or_else(|_| "<h1>Hello GenAI</h1>".to_string());
-    HttpResponse::Ok().content_type("text/html").body(rendered)
+/// Without API keys there are no sessions, so the web UI gets a random
+/// browser id instead to keep its conversations apart from others on the
+/// same IP.
+fn browser_cookie(req: &HttpRequest, keys: &KeyStore) -> Option<Cookie<'static>> {
+    if keys.enabled() || req.cookie(client::BROWSER_COOKIE).is_some() {
+        return None;
+    }
+    Some(Cookie::build(client::BROWSER_COOKIE, uuid::Uuid::new_v4().simple().to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(req.connection_info().scheme() == "https")
+        .permanent()
+        .finish())
 }
 
 
 #[get("/example")]
-pub async fn example() -> impl Responder {
-    let example = fs::read_to_string("static/examples/structured_response_example.md").unwrap_or_default();
+pub async fn example(files: web::Data<Arc<StaticFiles>>) -> impl Responder {
+    let example = files.read("examples/structured_response_example.md")
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .unwrap_or_default();
     HttpResponse::Ok().json(serde_json::json!({"response": example}))
 }
 
+/// Answers as long as the process is serving requests. `/health` is kept
+/// for probes set up before the split.
+#[routes]
 #[get("/health")]
-pub async fn health() -> impl Responder {
+#[get("/health/live")]
+pub async fn health_live(health: web::Data<Arc<HealthState>>) -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
-        "status": "healthy",
+        "status": "alive",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "uptime_secs": health.uptime().as_secs(),
+    }))
+}
+
+/// 503 until every configured model is listed by its backend, so traffic
+/// isn't sent while the LLM is unreachable or still pulling the model.
+#[get("/health/ready")]
+pub async fn health_ready(
+    health: web::Data<Arc<HealthState>>,
+    models: web::Data<Arc<ModelRegistry>>,
+    cache: web::Data<Arc<AppCache>>,
+    rate_limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    let readiness = health.readiness(&models).await;
+    let backends: serde_json::Map<String, serde_json::Value> = models.models().iter()
+        .map(|m| (m.spec.name.clone(), m.provider.status().into()))
+        .collect();
+    let mut resp = match readiness.ready {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    resp.json(serde_json::json!({
+        "status": if readiness.ready { "ready" } else { "not_ready" },
         "timestamp": chrono::Utc::now().to_rfc3339(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": health.uptime().as_secs(),
+        "checked_at": readiness.checked_at,
+        "models": readiness.models,
+        "backends": backends,
+        "cache_entries": cache.len(),
+        "rate_limited_clients": rate_limiter.client_count(),
     }))
 }
 
+/// Prometheus scrape endpoint.
+#[get("/metrics")]
+pub async fn scrape_metrics(
+    metrics: web::Data<Arc<Metrics>>,
+    cache: web::Data<Arc<AppCache>>,
+    rate_limiter: web::Data<Arc<RateLimiter>>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&cache, &rate_limiter))
+}
+
 #[get("/api/docs")]
-pub async fn api_docs() -> impl Responder {
+pub async fn api_docs(templates: web::Data<Arc<Templates>>) -> impl Responder {
     HttpResponse::Ok().content_type("text/html").body(
-        fs::read_to_string("templates/swagger.html").unwrap_or_else(|_| "<h1>API Docs Not Found</h1>".to_string())
+        templates.render("swagger.html", &tera::Context::new()).unwrap_or_else(|_| "<h1>API Docs Not Found</h1>".to_string())
     )
 }
 
 #[derive(Deserialize)]
 pub struct ChatRequest {
     pub message: String,
+    pub conversation_id: Option<String>,
+    /// Persona whose system prompt and default settings to use.
+    pub persona: Option<String>,
+    /// Model to answer with; when unset the routing rules pick one.
+    pub model: Option<String>,
+    #[serde(default)]
+    pub cache: CacheMode,
+    #[serde(flatten)]
+    pub params: GenerationParams,
 }
 
 #[derive(Serialize)]
 pub struct ChatResponse {
     pub response: String,
+    pub conversation_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    pub persona: String,
+    pub model: String,
+    /// Generation settings the answer was produced with.
+    pub params: GenerationParams,
+    /// How many values each redaction rule replaced in the prompt.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub redactions: BTreeMap<String, usize>,
+}
+
+/// Whose conversations a request sees: the caller's `ClientId`, except that
+/// anonymous web UI users are told apart by their browser cookie.
+fn conversation_owner(req: &HttpRequest, client: &ClientId) -> ClientId {
+    match req.cookie(client::BROWSER_COOKIE) {
+        Some(c) if client.is_ip() => ClientId::for_browser(c.value()),
+        _ => client.clone(),
+    }
+}
+
+/// Returns the prior turns of the conversation a chat request continues.
+fn conversation_history(
+    conversations: &ConversationStore,
+    client: &ClientId,
+    payload: &ChatRequest,
+) -> Result<Vec<ChatMessage>, ApiError> {
+    match &payload.conversation_id {
+        Some(id) => conversations.history(id, client).ok_or(ApiError::NotFound("Conversation")),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Resolves the persona a request asks for, or the configured default.
+fn request_persona(personas: &PersonaStore, config: &AppConfig, payload: &ChatRequest) -> Result<Persona, ApiError> {
+    let name = payload.persona.as_deref().unwrap_or(&config.default_persona);
+    personas.get(name).ok_or(ApiError::NotFound("Persona"))
+}
+
+/// Stores a completed exchange, starting a new conversation if the request
+/// didn't continue one. Only called on success so failed calls leave no trace.
+fn record_turn(
+    conversations: &ConversationStore,
+    client: &ClientId,
+    conversation_id: Option<String>,
+    message: String,
+    response: String,
+) -> String {
+    let id = conversation_id.unwrap_or_else(|| conversations.create(client, &message));
+    conversations.append_turn(&id, client, message, response);
+    id
+}
+
+/// Picks the model for a chat request and checks the caller may use it.
+fn request_model(
+    models: &ModelRegistry,
+    principal: Option<&Principal>,
+    payload: &ChatRequest,
+    persona: &Persona,
+    history: &[ChatMessage],
+) -> Result<Arc<Model>, ApiError> {
+    let prompt_tokens = conversation::estimate_tokens(history) + conversation::estimate_text_tokens(&payload.message);
+    let model = models.route(payload.model.as_deref(), prompt_tokens, Some(&persona.name))?;
+    auth::check_model(principal, &model.spec.name)?;
+    Ok(model)
 }
 
-#[post("/api/chat")]
+/// Tokens of history that fit once the reply's share is set aside: the
+/// requested `max_tokens`, or a default reserve.
+fn context_budget(model: &Model, params: &GenerationParams) -> usize {
+    let reserve = params.max_tokens.map_or(RESPONSE_RESERVE_TOKENS, |t| t as usize);
+    model.spec.context_size.saturating_sub(reserve)
+}
+
+
+#[post("")]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "chat", skip_all, fields(model = tracing::field::Empty, persona = tracing::field::Empty))]
 pub async fn chat_api(
     req: HttpRequest,
     config: web::Data<AppConfig>,
     cache: web::Data<Arc<AppCache>>,
-    rate_limiter: web::Data<Arc<RateLimiter>>,
+    conversations: web::Data<Arc<ConversationStore>>,
+    personas: web::Data<Arc<PersonaStore>>,
+    models: web::Data<Arc<ModelRegistry>>,
+    quotas: web::Data<Arc<QuotaTracker>>,
+    audit: web::Data<Arc<AuditLog>>,
+    redactor: web::Data<Arc<Redactor>>,
+    client: web::ReqData<ClientId>,
+    principal: Option<web::ReqData<Principal>>,
     payload: web::Json<ChatRequest>,
-) -> impl Responder {
-    let ip = req.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
-    if !rate_limiter.allow(&ip) {
-        return HttpResponse::TooManyRequests().json(serde_json::json!({"error": "Rate limit exceeded"}));
-    }
+) -> Result<HttpResponse, ApiError> {
+    let started = Instant::now();
     let message = &payload.message;
-    if message.len() > 4000 {
-        return HttpResponse::BadRequest().json(serde_json::json!({"error": "Message too long (max 4000 chars)"}));
+    if message.len() > conversation::MAX_MESSAGE_LEN {
+        return Err(ApiError::InvalidRequest(format!("Message too long (max {} chars)", conversation::MAX_MESSAGE_LEN)));
     }
-    if let Some(resp) = cache.get(message) {
-        return HttpResponse::Ok().json(ChatResponse { response: resp });
+    payload.params.validate(&config.generation_limits).map_err(ApiError::InvalidRequest)?;
+    let persona = request_persona(&personas, &config, &payload)?;
+    let params = payload.params.with_defaults(&persona.params);
+    let owner = conversation_owner(&req, &client);
+    let history = conversation_history(&conversations, &owner, &payload)?;
+    let model = request_model(&models, principal.as_deref(), &payload, &persona, &history)?;
+    tracing::Span::current().record("model", model.spec.name.as_str()).record("persona", persona.name.as_str());
+    let mut messages = conversation::build_context(&persona.prompt, &history, message, context_budget(&model, &params))?;
+    let redactions = redactor.redact_messages(&mut messages);
+    // Keyed and stored with placeholders, so no one is served another user's values
+    let cache_key = cache::request_key(&model.spec.name, &messages, &params);
+    let principal_name = principal.as_deref().map(|p| p.name.as_str());
+    if payload.cache.reads() {
+        if let Some(resp) = cache.get(&cache_key).map(|r| redactions.restore(&r)) {
+            audit.record(AuditRecord {
+                prompt: message,
+                response: &resp,
+                cached: true,
+                ..AuditRecord::new("/api/chat", client.as_str(), principal_name, &model.spec.name, started)
+            });
+            let conversation_id = record_turn(&conversations, &owner, payload.conversation_id.clone(), message.clone(), resp.clone());
+            return Ok(HttpResponse::Ok()
+                .insert_header((cache::X_CACHE, "HIT"))
+                .json(ChatResponse { response: resp, conversation_id, usage: None, persona: persona.name, model: model.spec.name.clone(), params, redactions: redactions.counts() }));
+        }
     }
+    let reservation = quotas.check(&client, model.cost(conversation::estimate_tokens(&messages) as u64)).map_err(ApiError::QuotaExceeded)?;
     // Call LLM API
-    let llm_url = format!("{}/chat/completions", config.llm_base_url);
-    let client = reqwest::Client::new();
-    let system_prompt = "You are a helpful assistant. Please provide structured responses using markdown formatting. Use headers (# for main points), bullet points (- for lists), bold (**text**) for emphasis, and code blocks (```code```) for code examples. Organize your responses with clear sections and concise explanations.";
-    let req_body = serde_json::json!({
-        "model": config.llm_model_name,
-        "messages": [
-            {"role": "system", "content": system_prompt},
-            {"role": "user", "content": message}
-        ]
+    let completion = model.provider.chat(&messages, &params).await?;
+    reservation.settle(model.charge(completion.usage.as_ref(), &messages, &completion.content));
+    if payload.cache.writes() {
+        cache.set(cache_key, completion.content.clone());
+    }
+    let content = redactions.restore(&completion.content);
+    audit.record(AuditRecord {
+        prompt: message,
+        response: &content,
+        usage: completion.usage.as_ref(),
+        ..AuditRecord::new("/api/chat", client.as_str(), principal_name, &model.spec.name, started)
     });
-    let resp = client.post(&llm_url)
-        .json(&req_body)
-        .send()
-        .await;
-    match resp {
-        Ok(r) => {
-            if r.status().is_success() {
-                let v: serde_json::Value = r.json().await.unwrap_or_default();
-                let content = v["choices"][0]["message"]["content"].as_str().unwrap_or("").to_string();
-                cache.set(message.clone(), content.clone());
-                HttpResponse::Ok().json(ChatResponse { response: content })
-            } else {
-                HttpResponse::InternalServerError().json(serde_json::json!({"error": "LLM API error"}))
-            }
-        },
-        Err(_) => HttpResponse::InternalServerError().json(serde_json::json!({"error": "Failed to call LLM API"})),
+    let conversation_id = record_turn(&conversations, &owner, payload.conversation_id.clone(), message.clone(), content.clone());
+    Ok(HttpResponse::Ok()
+        .insert_header((cache::X_CACHE, "MISS"))
+        .json(ChatResponse { response: content, conversation_id, usage: completion.usage, persona: persona.name, model: model.spec.name.clone(), params, redactions: redactions.counts() }))
+}
+
+#[post("/stream")]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "chat_stream", skip_all, fields(model = tracing::field::Empty, persona = tracing::field::Empty))]
+pub async fn chat_stream(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Arc<AppCache>>,
+    conversations: web::Data<Arc<ConversationStore>>,
+    personas: web::Data<Arc<PersonaStore>>,
+    models: web::Data<Arc<ModelRegistry>>,
+    quotas: web::Data<Arc<QuotaTracker>>,
+    audit: web::Data<Arc<AuditLog>>,
+    redactor: web::Data<Arc<Redactor>>,
+    client: web::ReqData<ClientId>,
+    principal: Option<web::ReqData<Principal>>,
+    payload: web::Json<ChatRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let started = Instant::now();
+    if payload.message.len() > conversation::MAX_MESSAGE_LEN {
+        return Err(ApiError::InvalidRequest(format!("Message too long (max {} chars)", conversation::MAX_MESSAGE_LEN)));
+    }
+    payload.params.validate(&config.generation_limits).map_err(ApiError::InvalidRequest)?;
+    let persona = request_persona(&personas, &config, &payload)?;
+    let owner = conversation_owner(&req, &client);
+    let history = conversation_history(&conversations, &owner, &payload)?;
+    let model = request_model(&models, principal.as_deref(), &payload, &persona, &history)?;
+    tracing::Span::current().record("model", model.spec.name.as_str()).record("persona", persona.name.as_str());
+    let principal_name = principal.map(|p| p.into_inner().name);
+    let ChatRequest { message, conversation_id, cache: cache_mode, params, .. } = payload.into_inner();
+    let params = params.with_defaults(&persona.params);
+    let mut messages = conversation::build_context(&persona.prompt, &history, &message, context_budget(&model, &params))?;
+    let redactions = redactor.redact_messages(&mut messages);
+    let cache_key = cache::request_key(&model.spec.name, &messages, &params);
+    if cache_mode.reads() {
+        if let Some(resp) = cache.get(&cache_key).map(|r| redactions.restore(&r)) {
+            audit.record(AuditRecord {
+                prompt: &message,
+                response: &resp,
+                cached: true,
+                ..AuditRecord::new("/api/chat/stream", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+            });
+            let conversation_id = record_turn(&conversations, &owner, conversation_id, message, resp.clone());
+            let body = [
+                sse::event("token", &serde_json::json!({"content": resp})),
+                sse::event("done", &serde_json::json!({"usage": null, "cached": true, "conversation_id": conversation_id, "persona": persona.name, "model": model.spec.name, "params": params, "redactions": redactions.counts()})),
+            ].concat();
+            return Ok(sse::response().insert_header((cache::X_CACHE, "HIT")).body(body));
+        }
     }
+    let reservation = quotas.check(&client, model.cost(conversation::estimate_tokens(&messages) as u64)).map_err(ApiError::QuotaExceeded)?;
+    let mut upstream = model.provider.chat_stream(&messages, &params).await?;
+
+    // Relay from a separate task: when the browser goes away the receiver is
+    // dropped, the next send fails, and dropping `upstream` aborts the LLM call.
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(32);
+    let cache = cache.get_ref().clone();
+    let conversations = conversations.get_ref().clone();
+    let audit = audit.get_ref().clone();
+    let client = client.into_inner();
+    let request_id = telemetry::current_request_id();
+    actix_web::rt::spawn(async move {
+        // `content` is the answer as generated, `answer` with redacted values restored
+        let mut content = String::new();
+        let mut answer = String::new();
+        let mut restorer = redactions.restorer();
+        let mut usage = None;
+        let mut interrupted = false;
+        while let Some(ev) = upstream.next().await {
+            match ev {
+                Ok(StreamEvent::Token(token)) => {
+                    content.push_str(&token);
+                    let text = restorer.push(&token);
+                    if text.is_empty() {
+                        continue;
+                    }
+                    answer.push_str(&text);
+                    if tx.send(sse::event("token", &serde_json::json!({"content": text}))).await.is_err() {
+                        interrupted = true;
+                        break;
+                    }
+                }
+                Ok(StreamEvent::Usage(u)) => usage = Some(u),
+                Ok(StreamEvent::Done) => break,
+                Err(e) => {
+                    let _ = tx.send(sse::event("error", &ApiError::from(e).to_json())).await;
+                    interrupted = true;
+                    break;
+                }
+            }
+        }
+        // Whatever was generated counts against the quota, even if cut short
+        reservation.settle(model.charge(usage.as_ref(), &messages, &content));
+        let rest = restorer.finish();
+        answer.push_str(&rest);
+        if !interrupted && !rest.is_empty() {
+            interrupted = tx.send(sse::event("token", &serde_json::json!({"content": rest}))).await.is_err();
+        }
+        audit.record(AuditRecord {
+            request_id,
+            prompt: &message,
+            response: &answer,
+            usage: usage.as_ref(),
+            ..AuditRecord::new("/api/chat/stream", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+        });
+        if interrupted {
+            return;
+        }
+        if cache_mode.writes() {
+            cache.set(cache_key, content.clone());
+        }
+        let conversation_id = record_turn(&conversations, &owner, conversation_id, message, answer);
+        let _ = tx.send(sse::event("done", &serde_json::json!({"usage": usage, "cached": false, "conversation_id": conversation_id, "persona": persona.name, "model": model.spec.name, "params": params, "redactions": redactions.counts()}))).await;
+    }.in_current_span());
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|b| (Ok::<_, actix_web::Error>(b), rx))
+    });
+    Ok(sse::response().insert_header((cache::X_CACHE, "MISS")).streaming(events))
 }
 
-// Security headers middleware
-use std::future::{ready, Ready};
-use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use futures::future::LocalBoxFuture;
+#[get("")]
+pub async fn list_conversations(
+    req: HttpRequest,
+    conversations: web::Data<Arc<ConversationStore>>,
+    client: web::ReqData<ClientId>,
+) -> impl Responder {
+    HttpResponse::Ok().json(conversations.list(&conversation_owner(&req, &client)))
+}
 
-pub struct SecurityHeaders;
+#[get("/{id}")]
+pub async fn get_conversation(
+    req: HttpRequest,
+    conversations: web::Data<Arc<ConversationStore>>,
+    client: web::ReqData<ClientId>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let conversation = conversations.get(&id, &conversation_owner(&req, &client)).ok_or(ApiError::NotFound("Conversation"))?;
+    Ok(HttpResponse::Ok().json(conversation))
+}
 
-impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type Transform = SecurityHeadersMiddleware<S>;
-    type InitError = ();
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+#[derive(Deserialize)]
+pub struct RenameConversation {
+    pub title: String,
+}
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(SecurityHeadersMiddleware { service }))
+#[patch("/{id}")]
+pub async fn rename_conversation(
+    req: HttpRequest,
+    conversations: web::Data<Arc<ConversationStore>>,
+    client: web::ReqData<ClientId>,
+    id: web::Path<String>,
+    payload: web::Json<RenameConversation>,
+) -> Result<HttpResponse, ApiError> {
+    let title = payload.into_inner().title.trim().to_string();
+    if title.is_empty() || title.len() > 200 {
+        return Err(ApiError::InvalidRequest("Title must be 1-200 chars".to_string()));
     }
+    let owner = conversation_owner(&req, &client);
+    if !conversations.rename(&id, &owner, title) {
+        return Err(ApiError::NotFound("Conversation"));
+    }
+    Ok(HttpResponse::Ok().json(conversations.get(&id, &owner)))
 }
 
-pub struct SecurityHeadersMiddleware<S> {
-    service: S,
+#[delete("/{id}")]
+pub async fn delete_conversation(
+    req: HttpRequest,
+    conversations: web::Data<Arc<ConversationStore>>,
+    client: web::ReqData<ClientId>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if !conversations.delete(&id, &conversation_owner(&req, &client)) {
+        return Err(ApiError::NotFound("Conversation"));
+    }
+    Ok(HttpResponse::NoContent().finish())
 }
 
-impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+#[get("")]
+pub async fn token_usage(
+    quotas: web::Data<Arc<QuotaTracker>>,
+    client: web::ReqData<ClientId>,
+    principal: Option<web::ReqData<Principal>>,
+) -> impl Responder {
+    let name = principal.as_deref().map_or(client.as_str(), |p| p.name.as_str());
+    HttpResponse::Ok().json(quotas.usage(&client, name))
+}
 
-    forward_ready!(service);
+#[get("")]
+pub async fn list_personas(personas: web::Data<Arc<PersonaStore>>) -> impl Responder {
+    HttpResponse::Ok().json(personas.list())
+}
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let fut = self.service.call(req);
+/// The configured models with whether their backend currently serves them,
+/// plus any other models the backends offer.
+#[get("")]
+pub async fn list_models(
+    models: web::Data<Arc<ModelRegistry>>,
+    principal: Option<web::ReqData<Principal>>,
+) -> impl Responder {
+    let allowed = |name: &str| principal.as_deref().is_none_or(|p| p.allows_model(name));
+    let live = models.cached_live_models().await;
+    let default = models.default_model();
+    let entries: Vec<serde_json::Value> = models.models().iter().zip(&live)
+        .filter(|(m, _)| allowed(&m.spec.name))
+        .map(|(m, live)| serde_json::json!({
+            "name": m.spec.name,
+            "provider": m.spec.provider,
+            "context_size": m.spec.context_size,
+            "cost_weight": m.spec.cost_weight,
+            "default": m.spec.name == default.spec.name,
+            "available": live.as_ref().ok().map(|ids| ids.contains(&m.spec.name)),
+            "error": live.as_ref().err(),
+        }))
+        .collect();
+    let mut discovered: Vec<&String> = live.iter()
+        .flatten()
+        .flatten()
+        .filter(|id| models.get(id).is_none() && allowed(id))
+        .collect();
+    discovered.sort();
+    discovered.dedup();
+    HttpResponse::Ok().json(serde_json::json!({"default": default.spec.name, "models": entries, "discovered": discovered}))
+}
 
-        Box::pin(async move {
-            let mut res = fut.await?;
-            let headers = res.headers_mut();
-            headers.insert(HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff"));
-            headers.insert(HeaderName::from_static("x-frame-options"), HeaderValue::from_static("sameorigin"));
-            headers.insert(HeaderName::from_static("x-xss-protection"), HeaderValue::from_static("1; mode=block"));
-            headers.insert(HeaderName::from_static("content-security-policy"), HeaderValue::from_static("default-src 'self'; img-src 'self' data:; script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net; style-src 'self' 'unsafe-inline' https://cdnjs.cloudflare.com; font-src 'self' https://cdnjs.cloudflare.com"));
-            Ok(res)
-        })
-    }
+#[get("")]
+pub async fn cache_stats(cache: web::Data<Arc<AppCache>>) -> impl Responder {
+    HttpResponse::Ok().json(cache.stats())
 }
+
@@ -0,0 +1,69 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use crate::models::ModelRegistry;
+
+// Orchestrators poll readiness often; answers this fresh are reused rather
+// than asking every backend again
+const READINESS_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Whether one configured model is being served.
+#[derive(Clone, Serialize)]
+pub struct ModelCheck {
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checked_at: String,
+    pub models: BTreeMap<String, ModelCheck>,
+}
+
+/// Process start time and the last readiness check.
+pub struct HealthState {
+    started: Instant,
+    last: tokio::sync::Mutex<Option<(Instant, Readiness)>>,
+}
+
+impl HealthState {
+    pub fn new() -> Self {
+        HealthState { started: Instant::now(), last: tokio::sync::Mutex::new(None) }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Ready when every configured model's backends answer their model
+    /// list and include the model. The lock is held across the check so
+    /// concurrent probes share one round of upstream calls.
+    pub async fn readiness(&self, models: &ModelRegistry) -> Readiness {
+        let mut last = self.last.lock().await;
+        if let Some((at, readiness)) = last.as_ref() {
+            if at.elapsed() < READINESS_CACHE_TTL {
+                return readiness.clone();
+            }
+        }
+        let live = models.live_models().await;
+        let checks: BTreeMap<String, ModelCheck> = models.models().iter().zip(live)
+            .map(|(m, live)| {
+                let check = match live {
+                    Ok(ids) if ids.contains(&m.spec.name) => ModelCheck { available: true, error: None },
+                    Ok(_) => ModelCheck { available: false, error: Some("model not served by backend".to_string()) },
+                    Err(e) => ModelCheck { available: false, error: Some(e) },
+                };
+                (m.spec.name.clone(), check)
+            })
+            .collect();
+        let readiness = Readiness {
+            ready: checks.values().all(|c| c.available),
+            checked_at: chrono::Utc::now().to_rfc3339(),
+            models: checks,
+        };
+        *last = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+}
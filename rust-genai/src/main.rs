@@ -1,52 +1,250 @@
 mod config;
+mod assets;
+mod audit;
+mod auth;
+mod backends;
 mod cache;
+mod client;
 mod rate_limit;
+mod quota;
+mod conversation;
+mod error;
+mod health;
+mod params;
+mod metrics;
+mod models;
+mod persona;
+mod redact;
+mod telemetry;
+mod templates;
+mod providers;
+mod sse;
 mod handlers;
+mod openai_api;
+mod middleware;
+mod watch;
 
-use actix_web::{App, HttpServer, middleware::Logger};
+use actix_web::{App, HttpServer, http::header};
 use actix_cors::Cors;
-use actix_files::Files;
 use dotenv::dotenv;
-use std::env;
-use crate::config::AppConfig;
+use crate::config::{AppConfig, Cli};
+use crate::assets::StaticFiles;
+use crate::audit::AuditLog;
+use crate::auth::KeyStore;
 use crate::cache::AppCache;
 use crate::rate_limit::RateLimiter;
+use crate::quota::QuotaTracker;
+use crate::conversation::ConversationStore;
+use crate::metrics::Metrics;
+use crate::models::ModelRegistry;
+use crate::persona::PersonaStore;
+use crate::redact::Redactor;
+use crate::templates::Templates;
+use crate::error::ApiError;
+use crate::health::HealthState;
 use crate::handlers::*;
+use crate::middleware::{Authenticate, RateLimit, RecordMetrics, SecurityHeaders, TraceRequests};
 use std::sync::Arc;
+use std::time::Duration;
+
+// Upper bound on how long expired cache entries linger before the sweeper drops them
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const QUOTA_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+const CONVERSATION_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+const AUDIT_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const PROMPTS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const TEMPLATES_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env_logger::init();
-    let config = AppConfig::from_env();
-    let cache = Arc::new(AppCache::new());
-    let rate_limiter = Arc::new(RateLimiter::new(10, 60)); // 10 req/min per IP
+    let cli = Cli::parse(std::env::args().skip(1));
+    let (config, resolved) = match AppConfig::load(&cli) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", resolved.to_toml());
+        return Ok(());
+    }
+    telemetry::init(&config).map_err(std::io::Error::other)?;
+    let mut cache = AppCache::new(
+        Duration::from_secs(config.cache_ttl_secs),
+        config.cache_max_entries,
+        config.cache_max_bytes,
+    );
+    if let Some(path) = &config.cache_path {
+        cache = cache.with_disk(path).map_err(std::io::Error::other)?;
+    }
+    let cache = Arc::new(cache);
+    cache.spawn_sweeper(CACHE_SWEEP_INTERVAL.min(Duration::from_secs(config.cache_ttl_secs.max(1))));
+    let mut api_keys = config.api_keys.clone();
+    if let Some(path) = &config.api_keys_file {
+        api_keys.extend(KeyStore::load_file(path)?);
+    }
+    let keys = Arc::new(KeyStore::new(api_keys, Duration::from_secs(config.session_ttl_secs), config.max_sessions));
+    if keys.enabled() {
+        keys.spawn_cleanup(SESSION_CLEANUP_INTERVAL);
+    } else {
+        log::warn!("No API keys configured, /api endpoints are open to everyone");
+    }
+    // Per-key policies name keys that may only be known once the key file is read
+    let rate_limit_keys = keys.by_name("rate_limit.keys", config.rate_limit_keys.clone());
+    let token_quota_keys = keys.by_name("quota.keys", config.token_quota_keys.clone());
+    let (rate_limit_keys, token_quota_keys) = match (rate_limit_keys, token_quota_keys) {
+        (Ok(rates), Ok(quotas)) => (rates, quotas),
+        (rates, quotas) => {
+            eprintln!("Invalid configuration:");
+            for error in [rates.err(), quotas.err()].into_iter().flatten() {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(2);
+        }
+    };
+    let rate_limiter = Arc::new(RateLimiter::new(
+        config.rate_limit,
+        config.rate_limit_routes.clone(),
+        rate_limit_keys,
+    ).recognize_keys(keys.client_ids()));
+    rate_limiter.spawn_cleanup(RATE_LIMIT_CLEANUP_INTERVAL);
+    let quotas = Arc::new(QuotaTracker::new(config.token_quota, token_quota_keys));
+    quotas.spawn_cleanup(QUOTA_CLEANUP_INTERVAL);
+    let audit = Arc::new(match &config.audit_dir {
+        Some(dir) => AuditLog::new(dir, Redactor::for_audit(&config), config.audit_retention_days)?,
+        None => AuditLog::disabled(),
+    });
+    audit.spawn_cleanup(AUDIT_PURGE_INTERVAL);
+    let redactor = Arc::new(Redactor::from_config(&config));
+    let conversations = Arc::new(ConversationStore::new(
+        config.conversations_max_entries,
+        Duration::from_secs(config.conversation_ttl_secs),
+    ));
+    conversations.spawn_sweeper(CONVERSATION_SWEEP_INTERVAL);
+    let personas = Arc::new(PersonaStore::new(&config.prompts_dir, config.generation_limits.clone()));
+    if personas.get(&config.default_persona).is_none() {
+        log::warn!("Default persona '{}' not found in {}", config.default_persona, config.prompts_dir);
+    }
+    personas.spawn_watcher(PROMPTS_POLL_INTERVAL);
+    let templates = Arc::new(Templates::new(config.templates_dir.as_deref()));
+    if config.dev_mode {
+        match &config.templates_dir {
+            Some(dir) => log::info!("Dev mode: reloading templates from {} on change", dir),
+            None => log::warn!("Dev mode: set TEMPLATES_DIR to edit the embedded templates without rebuilding"),
+        }
+        templates.spawn_watcher(TEMPLATES_POLL_INTERVAL);
+    } else if let Some(e) = templates.error() {
+        eprintln!("Invalid templates:\n{}", e);
+        std::process::exit(2);
+    }
+    let static_files = Arc::new(StaticFiles::new(config.static_dir.as_deref()));
+    let metrics = Arc::new(Metrics::new());
+    let client = providers::http_client(&config);
+    let models = Arc::new(ModelRegistry::from_config(&config, client, metrics.clone()));
+    let health = Arc::new(HealthState::new());
+    if config.llm_health_check_secs > 0 {
+        models.spawn_health_checks(Duration::from_secs(config.llm_health_check_secs));
+    }
 
     log::info!("Starting server on port {}", config.port);
     let port = config.port;
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
+    let served = HttpServer::new(move || {
+        // Same-origin only unless cross-origin callers are listed explicitly
+        let cors = config.cors_allowed_origins.iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(["GET", "POST", "PATCH", "DELETE"])
+            .allowed_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::HeaderName::from_static("x-api-key"),
+                header::HeaderName::from_static(telemetry::X_REQUEST_ID),
+                header::HeaderName::from_static("traceparent"),
+            ])
+            .expose_headers([header::HeaderName::from_static(telemetry::X_REQUEST_ID)])
             .max_age(3600);
 
         App::new()
             .app_data(actix_web::web::Data::new(config.clone()))
             .app_data(actix_web::web::Data::new(cache.clone()))
+            .app_data(actix_web::web::Data::new(keys.clone()))
             .app_data(actix_web::web::Data::new(rate_limiter.clone()))
-            .wrap(Logger::default())
+            .app_data(actix_web::web::Data::new(quotas.clone()))
+            .app_data(actix_web::web::Data::new(audit.clone()))
+            .app_data(actix_web::web::Data::new(redactor.clone()))
+            .app_data(actix_web::web::Data::new(conversations.clone()))
+            .app_data(actix_web::web::Data::new(personas.clone()))
+            .app_data(actix_web::web::Data::new(templates.clone()))
+            .app_data(actix_web::web::Data::new(static_files.clone()))
+            .app_data(actix_web::web::Data::new(models.clone()))
+            .app_data(actix_web::web::Data::new(health.clone()))
+            .app_data(actix_web::web::Data::new(metrics.clone()))
+            .app_data(actix_web::web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::InvalidRequest(err.to_string()).into()
+            }))
+            .wrap(Authenticate)
             .wrap(cors)
             .wrap(SecurityHeaders)
+            .wrap(RecordMetrics)
+            .wrap(TraceRequests)
             .service(index)
-            .service(chat_api)
-            .service(health)
+            .service(
+                actix_web::web::scope("/api/chat")
+                    .wrap(RateLimit::route("chat"))
+                    .service(chat_api)
+                    .service(chat_stream)
+            )
+            .service(
+                actix_web::web::scope("/api/conversations")
+                    .wrap(RateLimit::route("conversations"))
+                    .service(list_conversations)
+                    .service(get_conversation)
+                    .service(rename_conversation)
+                    .service(delete_conversation)
+            )
+            .service(
+                actix_web::web::scope("/v1")
+                    .wrap(RateLimit::route("openai"))
+                    .service(openai_api::chat_completions)
+                    .service(openai_api::list_models)
+            )
+            .service(
+                actix_web::web::scope("/api/usage")
+                    .wrap(RateLimit::route("usage"))
+                    .service(token_usage)
+            )
+            .service(
+                actix_web::web::scope("/api/personas")
+                    .wrap(RateLimit::route("info"))
+                    .service(list_personas)
+            )
+            .service(
+                actix_web::web::scope("/api/models")
+                    .wrap(RateLimit::route("info"))
+                    .service(list_models)
+            )
+            .service(
+                actix_web::web::scope("/api/cache/stats")
+                    .wrap(RateLimit::route("info"))
+                    .service(cache_stats)
+            )
+            .service(health_live)
+            .service(health_ready)
+            .service(scrape_metrics)
             .service(example)
             .service(api_docs)
-            .service(Files::new("/static", "static"))
+            .service(assets::static_file)
     })
     .bind(("0.0.0.0", port))?
     .run()
-    .await
+    .await;
+    telemetry::shutdown();
+    served
 }
@@ -0,0 +1,163 @@
+use prometheus::core::Collector;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::cache::AppCache;
+use crate::providers::Usage;
+use crate::rate_limit::RateLimiter;
+
+// LLM calls range from sub-second cache-warm replies to minutes-long generations
+const LLM_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Prometheus metrics for HTTP traffic and upstream LLM calls. Cache and
+/// rate-limiter figures are read from those components at scrape time
+/// rather than mirrored here.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    http_in_flight: IntGauge,
+    llm_duration: HistogramVec,
+    llm_first_token: HistogramVec,
+    llm_tokens: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["route", "method", "status"],
+        ).unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time until response headers were sent"),
+            &["route", "method", "status"],
+        ).unwrap();
+        let http_in_flight = IntGauge::new("http_requests_in_flight", "HTTP requests being handled").unwrap();
+        let llm_duration = HistogramVec::new(
+            HistogramOpts::new("llm_request_duration_seconds", "Duration of successful upstream LLM calls, including failover")
+                .buckets(LLM_BUCKETS.to_vec()),
+            &["model", "operation"],
+        ).unwrap();
+        let llm_first_token = HistogramVec::new(
+            HistogramOpts::new("llm_time_to_first_token_seconds", "Time from sending a streamed request to its first token")
+                .buckets(LLM_BUCKETS.to_vec()),
+            &["model"],
+        ).unwrap();
+        let llm_tokens = IntCounterVec::new(
+            Opts::new("llm_tokens_total", "Tokens sent to and generated by the LLM, estimated when the backend doesn't report them"),
+            &["model", "direction"],
+        ).unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(http_in_flight.clone())).unwrap();
+        registry.register(Box::new(llm_duration.clone())).unwrap();
+        registry.register(Box::new(llm_first_token.clone())).unwrap();
+        registry.register(Box::new(llm_tokens.clone())).unwrap();
+        Metrics { registry, http_requests, http_duration, http_in_flight, llm_duration, llm_first_token, llm_tokens }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn request_started(self: &Arc<Self>) -> InFlightRequest {
+        self.http_in_flight.inc();
+        InFlightRequest(Arc::clone(self))
+    }
+
+    /// `route` is the matched route pattern, never the raw path, so label
+    /// values stay bounded.
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [route, method, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_llm(&self, model: &str, operation: &str, elapsed: Duration) {
+        self.llm_duration.with_label_values(&[model, operation]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_first_token(&self, model: &str, elapsed: Duration) {
+        self.llm_first_token.with_label_values(&[model]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_tokens(&self, model: &str, usage: &Usage) {
+        self.llm_tokens.with_label_values(&[model, "prompt"]).inc_by(usage.prompt_tokens);
+        self.llm_tokens.with_label_values(&[model, "completion"]).inc_by(usage.completion_tokens);
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(&self, cache: &AppCache, limiter: &RateLimiter) -> String {
+        let mut families = self.registry.gather();
+        let stats = cache.stats();
+        let counter = |name: &str, help: &str, value: u64| {
+            let c = IntCounter::new(name, help).unwrap();
+            c.inc_by(value);
+            c.collect()
+        };
+        let gauge = |name: &str, help: &str, value: usize| {
+            let g = IntGauge::new(name, help).unwrap();
+            g.set(value as i64);
+            g.collect()
+        };
+        families.extend(counter("cache_hits_total", "Response cache hits", stats.hits));
+        families.extend(counter("cache_misses_total", "Response cache misses", stats.misses));
+        families.extend(counter("cache_evictions_total", "Cache entries evicted to stay within limits", stats.evictions));
+        families.extend(counter("cache_expirations_total", "Cache entries dropped after their TTL", stats.expirations));
+        families.extend(gauge("cache_entries", "Responses currently cached", stats.entries));
+        families.extend(gauge("cache_bytes", "Size of cached prompts and responses", stats.bytes));
+        let rejections = IntCounterVec::new(
+            Opts::new("rate_limit_rejections_total", "Requests refused by the rate limiter"),
+            &["route"],
+        ).unwrap();
+        for (route, count) in limiter.rejections() {
+            rejections.with_label_values(&[&route]).inc_by(count);
+        }
+        families.extend(rejections.collect());
+        families.extend(gauge("rate_limit_clients", "Clients currently tracked by the rate limiter", limiter.client_count()));
+        // The encoder rejects families without samples, e.g. before any rejection
+        families.retain(|f| !f.get_metric().is_empty());
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&families, &mut buf) {
+            log::error!("Encoding metrics failed: {}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+pub struct InFlightRequest(Arc<Metrics>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.http_in_flight.dec();
+    }
+}
+
+/// Times a streamed completion: the first token, and the whole stream once
+/// it is dropped.
+pub struct StreamTimer {
+    metrics: Arc<Metrics>,
+    model: String,
+    started: Instant,
+    first_token_seen: bool,
+}
+
+impl StreamTimer {
+    pub fn new(metrics: Arc<Metrics>, model: String, started: Instant) -> Self {
+        StreamTimer { metrics, model, started, first_token_seen: false }
+    }
+
+    pub fn token(&mut self) {
+        if !self.first_token_seen {
+            self.first_token_seen = true;
+            self.metrics.observe_first_token(&self.model, self.started.elapsed());
+        }
+    }
+}
+
+impl Drop for StreamTimer {
+    fn drop(&mut self) {
+        self.metrics.observe_llm(&self.model, "stream", self.started.elapsed());
+    }
+}
@@ -0,0 +1,384 @@
+use std::future::{ready, Ready};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, HttpMessage, ResponseError};
+use futures::future::LocalBoxFuture;
+use tracing::Instrument;
+use crate::auth::{self, KeyStore, Principal};
+use crate::cache;
+use crate::client;
+use crate::config::AppConfig;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::telemetry::{self, AccessLog, RequestNotes};
+
+// Security headers middleware
+
+pub struct SecurityHeaders;
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware { service }))
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            headers.insert(HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff"));
+            headers.insert(HeaderName::from_static("x-frame-options"), HeaderValue::from_static("sameorigin"));
+            headers.insert(HeaderName::from_static("x-xss-protection"), HeaderValue::from_static("1; mode=block"));
+            headers.insert(HeaderName::from_static("content-security-policy"), HeaderValue::from_static("default-src 'self'; img-src 'self' data:; script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net; style-src 'self' 'unsafe-inline' https://cdnjs.cloudflare.com; font-src 'self' https://cdnjs.cloudflare.com"));
+            Ok(res)
+        })
+    }
+}
+
+/// Rate-limits every request in the scope it wraps under the policy for
+/// `route`, adding `RateLimit-*` headers to all responses. The limiter and
+/// trusted-proxy list are taken from app data, so it can wrap any scope.
+pub struct RateLimit {
+    route: &'static str,
+}
+
+impl RateLimit {
+    pub fn route(route: &'static str) -> Self {
+        RateLimit { route }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service, route: self.route }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    route: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let limiter = req.app_data::<web::Data<Arc<RateLimiter>>>().cloned();
+        let config = req.app_data::<web::Data<AppConfig>>().cloned();
+        let (Some(limiter), Some(config)) = (limiter, config) else {
+            log::error!("RateLimit middleware used without RateLimiter/AppConfig app data");
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+
+        let ip = client::client_ip(req.peer_addr(), req.headers(), &config.trusted_proxies);
+        // Authenticated callers go by their key or session, so web UI users
+        // behind one NAT don't share a bucket
+        let principal = req.extensions().get::<Principal>().map(|p| p.client.clone());
+        let client = principal.unwrap_or_else(|| limiter.identify(&ip, client::api_key(req.headers())));
+        let decision = tracing::info_span!("rate_limit.check", route = self.route, client = client.as_str(), allowed = tracing::field::Empty)
+            .in_scope(|| {
+                let decision = limiter.check(self.route, &client);
+                tracing::Span::current().record("allowed", decision.allowed);
+                decision
+            });
+        // Handlers read the resolved identity back for per-client accounting
+        req.extensions_mut().insert(client);
+        if !decision.allowed {
+            let resp = ApiError::RateLimited(decision).error_response();
+            return Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            decision.apply_headers(res.headers_mut());
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+/// Requires an API key or web UI session for protected paths, with the
+/// scope the call needs. Does nothing when no keys are configured.
+pub struct Authenticate;
+
+impl<S, B> Transform<S, ServiceRequest> for Authenticate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticateMiddleware { service }))
+    }
+}
+
+pub struct AuthenticateMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let keys = req.app_data::<web::Data<Arc<KeyStore>>>().cloned();
+        let Some(keys) = keys.filter(|k| k.enabled() && auth::is_protected(req.path())) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+
+        let principal = match client::api_key(req.headers()) {
+            Some(key) => keys.authenticate_key(key),
+            None => req.cookie(auth::SESSION_COOKIE).and_then(|c| keys.authenticate_session(c.value())),
+        };
+        let required = auth::required_scope(req.method(), req.path());
+        let denied = match &principal {
+            None => Some(ApiError::Unauthorized),
+            Some(p) if !p.allows(required) => {
+                Some(ApiError::Forbidden(format!("API key '{}' lacks the '{}' scope", p.name, required)))
+            }
+            Some(p) if !p.allows_path(req.path()) => {
+                Some(ApiError::Forbidden(format!("'{}' may not call {}", p.name, req.path())))
+            }
+            Some(_) => None,
+        };
+        if let Some(err) = denied {
+            let resp = err.error_response();
+            return Box::pin(async move { Ok(req.into_response(resp).map_into_right_body()) });
+        }
+        if let Some(p) = principal {
+            req.extensions_mut().insert(p);
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+/// Counts and times every request by matched route, method and status, and
+/// tracks how many are in flight. Requests matching no route share one
+/// label so scanners can't inflate the series count.
+pub struct RecordMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RecordMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RecordMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RecordMetricsMiddleware { service }))
+    }
+}
+
+pub struct RecordMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RecordMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(metrics) = req.app_data::<web::Data<Arc<Metrics>>>().map(|m| m.get_ref().clone()) else {
+            log::error!("RecordMetrics middleware used without Metrics app data");
+            return Box::pin(self.service.call(req));
+        };
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+        let started = Instant::now();
+        let in_flight = metrics.request_started();
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            metrics.observe_request(&route, &method, status.as_u16(), started.elapsed());
+            drop(in_flight);
+            res
+        })
+    }
+}
+
+/// Opens the root span of each request, continuing the caller's trace when
+/// it sends a W3C `traceparent`, tags the request with an `X-Request-Id`
+/// that is echoed back and forwarded to upstream calls, and writes its
+/// access log line once the response is ready.
+pub struct TraceRequests;
+
+impl<S, B> Transform<S, ServiceRequest> for TraceRequests
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = TraceRequestsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TraceRequestsMiddleware { service }))
+    }
+}
+
+pub struct TraceRequestsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for TraceRequestsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = telemetry::request_id(req.headers());
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+        let config = req.app_data::<web::Data<AppConfig>>().cloned();
+        let trusted_proxies = config.as_ref().map_or(&[][..], |c| &c.trusted_proxies[..]);
+        let client_ip = client::client_ip(req.peer_addr(), req.headers(), trusted_proxies);
+        let log_fields = config.map(|c| c.log_fields.clone()).unwrap_or_default();
+        let span = tracing::info_span!(
+            "http.request",
+            otel.name = %format!("{} {}", method, route),
+            otel.kind = "server",
+            http.method = %method,
+            http.route = %route,
+            http.target = %req.uri(),
+            http.status_code = tracing::field::Empty,
+            request_id = %request_id,
+        );
+        telemetry::set_remote_parent(&span, req.headers());
+
+        // Extractors and the handler's synchronous part run inside `call`
+        let fut = span.in_scope(|| self.service.call(req));
+        let header = HeaderValue::from_str(&request_id).ok();
+        let notes = Rc::new(RefCell::new(RequestNotes::default()));
+        let started = Instant::now();
+        let traced = span.clone();
+        Box::pin(telemetry::in_request(request_id.clone(), notes.clone(), async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            traced.record("http.status_code", status.as_u16());
+            let cache = res.as_ref().ok()
+                .and_then(|r| r.headers().get(cache::X_CACHE))
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            AccessLog {
+                request_id: &request_id,
+                client_ip: &client_ip,
+                method: &method,
+                route: &route,
+                status: status.as_u16(),
+                latency: started.elapsed(),
+                cache: cache.as_deref(),
+                notes: &notes.borrow(),
+            }
+            .emit(&log_fields);
+            let mut res = res?;
+            if let Some(id) = header {
+                res.headers_mut().insert(HeaderName::from_static(telemetry::X_REQUEST_ID), id);
+            }
+            Ok(res)
+        }.instrument(span)))
+    }
+}
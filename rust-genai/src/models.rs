@@ -0,0 +1,204 @@
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::backends::{BackendPool, Breaker, RetryPolicy};
+use crate::config::{AppConfig, ProviderKind};
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::conversation::ChatMessage;
+use crate::providers::{self, LlmProvider, Usage};
+use crate::quota;
+use crate::telemetry;
+
+// How long listing models waits on each backend before reporting it unreachable
+const LIVE_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+// How long a model list answer is served before the backends are asked
+// again; health checks normally refresh it sooner
+const LIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Per configured model, the models its backend lists or why it couldn't.
+pub type LiveModels = Vec<Result<Vec<String>, String>>;
+
+/// A model clients can ask for by name, and the backend serving it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelSpec {
+    pub name: String,
+    pub provider: ProviderKind,
+    /// Upstreams serving the model, all speaking `provider`.
+    pub backends: Vec<String>,
+    pub context_size: usize,
+    /// Multiplier on the tokens charged against quotas, so larger models
+    /// can cost more.
+    pub cost_weight: f64,
+}
+
+/// Sends requests that don't name a model to `model` when every condition
+/// set on the rule holds. Rules are tried in order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+}
+
+impl RouteRule {
+    fn matches(&self, prompt_tokens: usize, persona: Option<&str>) -> bool {
+        self.max_prompt_tokens.is_none_or(|max| prompt_tokens <= max)
+            && self.min_prompt_tokens.is_none_or(|min| prompt_tokens >= min)
+            && self.persona.as_deref().is_none_or(|p| Some(p) == persona)
+    }
+}
+
+pub struct Model {
+    pub spec: ModelSpec,
+    pub provider: Arc<BackendPool>,
+    metrics: Arc<Metrics>,
+}
+
+impl Model {
+    /// Quota tokens charged for `tokens` used with this model.
+    pub fn cost(&self, tokens: u64) -> u64 {
+        (tokens as f64 * self.spec.cost_weight).ceil() as u64
+    }
+
+    /// Quota tokens charged for a completed call, counting its tokens in
+    /// the metrics on the way.
+    pub fn charge(&self, usage: Option<&Usage>, messages: &[ChatMessage], content: &str) -> u64 {
+        let usage = quota::usage_or_estimate(usage, messages, content);
+        self.metrics.record_tokens(&self.spec.name, &usage);
+        telemetry::note_usage(&usage);
+        self.cost(usage.total_tokens)
+    }
+}
+
+/// The configured models and the rules for picking one.
+pub struct ModelRegistry {
+    models: Vec<Arc<Model>>,
+    default: Arc<Model>,
+    rules: Vec<RouteRule>,
+    live: tokio::sync::Mutex<Option<(Instant, LiveModels)>>,
+}
+
+impl ModelRegistry {
+    /// Builds every model's providers on `client`, so they share its
+    /// connection pool.
+    pub fn from_config(config: &AppConfig, client: reqwest::Client, metrics: Arc<Metrics>) -> Self {
+        let breaker = Breaker {
+            failure_threshold: config.llm_failure_threshold,
+            cooldown: Duration::from_secs(config.llm_cooldown_secs),
+        };
+        let retry = RetryPolicy {
+            retries: config.llm_retries,
+            backoff: Duration::from_millis(config.llm_retry_backoff_ms),
+            max_delay: Duration::from_secs(config.llm_retry_max_delay_secs),
+        };
+        let timeouts = providers::Timeouts::from_config(config);
+        let models: Vec<Arc<Model>> = config.models.iter()
+            .map(|spec| {
+                let backends = spec.backends.iter()
+                    .map(|url| (url.clone(), providers::build(spec.provider, client.clone(), timeouts, url, &spec.name)))
+                    .collect();
+                let pool = BackendPool::new(spec.name.clone(), backends, config.llm_balance, breaker, retry, metrics.clone());
+                Arc::new(Model {
+                    provider: Arc::new(pool),
+                    spec: spec.clone(),
+                    metrics: metrics.clone(),
+                })
+            })
+            .collect();
+        let default = models.iter()
+            .find(|m| m.spec.name == config.llm_model_name)
+            .or(models.first())
+            .cloned()
+            .expect("config validation requires at least one model");
+        ModelRegistry { models, default, rules: config.routing_rules.clone(), live: tokio::sync::Mutex::new(None) }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Model>> {
+        self.models.iter().find(|m| m.spec.name == name).cloned()
+    }
+
+    pub fn default_model(&self) -> Arc<Model> {
+        self.default.clone()
+    }
+
+    pub fn models(&self) -> &[Arc<Model>] {
+        &self.models
+    }
+
+    /// The model for a request: the one it names, else the first routing
+    /// rule that matches, else the default.
+    pub fn route(&self, requested: Option<&str>, prompt_tokens: usize, persona: Option<&str>) -> Result<Arc<Model>, ApiError> {
+        let model = match requested {
+            Some(name) => self.get(name).ok_or(ApiError::NotFound("Model"))?,
+            None => self.rules.iter()
+                .find(|rule| rule.matches(prompt_tokens, persona))
+                .and_then(|rule| self.get(&rule.model))
+                .unwrap_or_else(|| self.default_model()),
+        };
+        telemetry::note_model(&model.spec.name);
+        Ok(model)
+    }
+
+    /// Asks the backends which models they serve, in the order of `models()`.
+    /// Each backend is asked once however many models share it.
+    pub async fn live_models(&self) -> LiveModels {
+        let backend = |m: &Model| (m.spec.provider, m.spec.backends.clone());
+        let mut asked: Vec<&Arc<Model>> = Vec::new();
+        for model in &self.models {
+            if !asked.iter().any(|a| backend(a) == backend(model)) {
+                asked.push(model);
+            }
+        }
+        let answers = join_all(asked.iter().map(|m| async move {
+            match actix_web::rt::time::timeout(LIVE_QUERY_TIMEOUT, m.provider.list_models()).await {
+                Ok(Ok(ids)) => Ok(ids),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            }
+        }))
+        .await;
+        self.models.iter()
+            .map(|model| {
+                let i = asked.iter().position(|a| backend(a) == backend(model)).unwrap_or_default();
+                answers[i].clone()
+            })
+            .collect()
+    }
+
+    /// `live_models` as of the last health check, or at most
+    /// `LIVE_CACHE_TTL` old, so listing models doesn't hit every backend.
+    /// The lock is held across a refresh so concurrent callers share it.
+    pub async fn cached_live_models(&self) -> LiveModels {
+        let mut last = self.live.lock().await;
+        if let Some((at, live)) = last.as_ref() {
+            if at.elapsed() < LIVE_CACHE_TTL {
+                return live.clone();
+            }
+        }
+        let live = self.live_models().await;
+        *last = Some((Instant::now(), live.clone()));
+        live
+    }
+
+    /// Probes every model's backends every `every` on the current runtime,
+    /// and refreshes the cached model lists.
+    pub fn spawn_health_checks(self: &Arc<Self>, every: Duration) {
+        let registry = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                let probes = join_all(registry.models.iter().map(|m| m.provider.probe()));
+                let (_, live) = futures::join!(probes, registry.live_models());
+                *registry.live.lock().await = Some((Instant::now(), live));
+            }
+        });
+    }
+}
@@ -0,0 +1,610 @@
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, ResponseError};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::auth::{self, Principal};
+use crate::cache::{self, AppCache, CacheMode};
+use crate::client::ClientId;
+use crate::config::AppConfig;
+use crate::conversation::{self, ChatMessage};
+use crate::error::ApiError;
+use crate::params::{GenerationLimits, GenerationParams};
+use crate::models::{Model, ModelRegistry};
+use crate::providers::{self, LlmProvider, ProviderError, StreamEvent, Usage};
+use crate::quota::{QuotaTracker, Reservation};
+use crate::redact::{self, Redactions, Redactor, Restorer};
+use std::collections::BTreeMap;
+use crate::sse::{self, LineDecoder};
+use crate::telemetry;
+
+/// `ApiError` rendered the way OpenAI clients expect:
+/// `{"error": {"message", "type", "code"}}`.
+#[derive(Debug)]
+pub struct OpenAiError(ApiError);
+
+impl OpenAiError {
+    pub fn to_json(&self) -> Value {
+        let status = self.0.status_code();
+        let kind = match status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            s if s.is_client_error() => "invalid_request_error",
+            _ => "api_error",
+        };
+        json!({"error": {"message": self.0.to_string(), "type": kind, "code": self.0.code()}})
+    }
+}
+
+impl std::fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for OpenAiError {
+    fn status_code(&self) -> StatusCode {
+        self.0.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code()).json(self.to_json());
+        self.0.apply_headers(resp.headers_mut());
+        resp
+    }
+}
+
+impl From<ApiError> for OpenAiError {
+    fn from(e: ApiError) -> Self {
+        OpenAiError(e)
+    }
+}
+
+impl From<ProviderError> for OpenAiError {
+    fn from(e: ProviderError) -> Self {
+        OpenAiError(e.into())
+    }
+}
+
+/// Body of `POST /v1/chat/completions`. The fields we validate or act on are
+/// typed; everything else (tool_choice, response_format, ...) is passed through as-is.
+#[derive(Deserialize, Serialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub messages: Vec<Value>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Value>>,
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+// Most messages one request may carry, history included
+const MAX_MESSAGES: usize = 200;
+
+impl ChatCompletionRequest {
+    /// Checks the request on its own; whether it fits the model's context
+    /// is checked once the model is known. User messages are held to the
+    /// same size as on `/api/chat`, while other roles, e.g. earlier answers,
+    /// are only bounded by the context.
+    fn validate(&self, limits: &GenerationLimits) -> Result<(), ApiError> {
+        let invalid = |msg: &str| Err(ApiError::InvalidRequest(msg.to_string()));
+        if self.messages.is_empty() {
+            return invalid("messages must not be empty");
+        }
+        if self.messages.len() > MAX_MESSAGES {
+            return Err(ApiError::InvalidRequest(format!("Too many messages (max {})", MAX_MESSAGES)));
+        }
+        if self.messages.iter().any(|m| !m["role"].is_string()) {
+            return invalid("every message needs a role");
+        }
+        let too_long = self.messages.iter()
+            .any(|m| m["role"] == "user" && message_text(&m["content"]).len() > conversation::MAX_MESSAGE_LEN);
+        if too_long {
+            return Err(ApiError::InvalidRequest(format!("Message too long (max {} chars)", conversation::MAX_MESSAGE_LEN)));
+        }
+        self.params.validate(limits).map_err(ApiError::InvalidRequest)
+    }
+
+    fn include_usage(&self) -> bool {
+        self.extra.get("stream_options").and_then(|o| o["include_usage"].as_bool()).unwrap_or(false)
+    }
+
+    /// Text of every message, for token estimates. Multi-part content keeps
+    /// its text parts; tool calls and images count as nothing.
+    fn text_messages(&self) -> Vec<ChatMessage> {
+        self.messages.iter()
+            .map(|m| ChatMessage::new(m["role"].as_str().unwrap_or_default(), message_text(&m["content"])))
+            .collect()
+    }
+
+    /// Plain role/content pairs for backends driven through `LlmProvider::chat`,
+    /// refusing what they can't express rather than silently dropping it.
+    fn plain_messages(&self) -> Result<Vec<ChatMessage>, ApiError> {
+        let unsupported = self.tools.as_ref().is_some_and(|t| !t.is_empty())
+            || self.messages.iter().any(|m| m["role"] == "tool" || !m["tool_calls"].is_null());
+        if unsupported {
+            return Err(ApiError::InvalidRequest("Tool calling requires an OpenAI-compatible backend".to_string()));
+        }
+        Ok(self.text_messages())
+    }
+}
+
+/// The newest user message: what the caller asked this time, since
+/// OpenAI clients resend the whole conversation.
+fn latest_prompt(messages: &[ChatMessage]) -> &str {
+    messages.iter().rev().find(|m| m.role == "user").map_or("", |m| m.content.as_str())
+}
+
+/// Restores redacted values in every choice of a completion, in its text
+/// and in the arguments of its tool calls.
+fn restore_completion(completion: &mut Value, redactions: &Redactions) {
+    if let Some(choices) = completion["choices"].as_array_mut() {
+        for choice in choices {
+            if let Some(content) = choice["message"]["content"].as_str() {
+                choice["message"]["content"] = Value::String(redactions.restore(content));
+            }
+            for call in choice["message"]["tool_calls"].as_array_mut().into_iter().flatten() {
+                if let Some(arguments) = call["function"]["arguments"].as_str() {
+                    call["function"]["arguments"] = Value::String(redactions.restore(arguments));
+                }
+            }
+        }
+    }
+}
+
+/// Restores the argument fragments of streamed tool calls, one `Restorer`
+/// per call since their fragments interleave.
+fn restore_tool_call_deltas(delta: &mut Value, restorers: &mut BTreeMap<u64, Restorer>, redactions: &Redactions) {
+    for call in delta["tool_calls"].as_array_mut().into_iter().flatten() {
+        let index = call["index"].as_u64().unwrap_or_default();
+        if let Some(arguments) = call["function"]["arguments"].as_str() {
+            let text = restorers.entry(index).or_insert_with(|| redactions.restorer()).push(arguments);
+            call["function"]["arguments"] = Value::String(text);
+        }
+    }
+}
+
+/// Appends what the tool call restorers still hold to a final delta.
+fn finish_tool_call_deltas(delta: &mut Value, restorers: &mut BTreeMap<u64, Restorer>) {
+    let rest: Vec<Value> = restorers.iter_mut()
+        .map(|(index, restorer)| (index, restorer.finish()))
+        .filter(|(_, text)| !text.is_empty())
+        .map(|(index, text)| json!({"index": index, "function": {"arguments": text}}))
+        .collect();
+    if rest.is_empty() {
+        return;
+    }
+    match delta["tool_calls"].as_array_mut() {
+        Some(calls) => calls.extend(rest),
+        None => delta["tool_calls"] = Value::Array(rest),
+    }
+}
+
+/// Tells the caller what was redacted from its prompt, since OpenAI bodies
+/// have no field of ours to carry it.
+fn report_redactions<'a>(resp: &'a mut HttpResponseBuilder, redactions: &Redactions) -> &'a mut HttpResponseBuilder {
+    if !redactions.is_empty() {
+        resp.insert_header((redact::X_REDACTIONS, redactions.header()));
+    }
+    resp
+}
+
+fn message_text(content: &Value) -> String {
+    match content {
+        Value::String(s) => s.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|p| p["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// `Cache-Control: no-store` skips the response cache and `no-cache` forces
+/// a fresh answer, since OpenAI bodies have no field of ours to carry it.
+fn cache_mode(req: &HttpRequest) -> CacheMode {
+    let directives = req.headers().get("cache-control").and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if directives.contains("no-store") {
+        CacheMode::Bypass
+    } else if directives.contains("no-cache") {
+        CacheMode::Refresh
+    } else {
+        CacheMode::Use
+    }
+}
+
+/// Identity and timestamp shared by every object of one completion.
+struct CompletionMeta {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl CompletionMeta {
+    fn new(model: &str) -> Self {
+        CompletionMeta {
+            id: format!("chatcmpl-{}", uuid::Uuid::new_v4().simple()),
+            created: chrono::Utc::now().timestamp(),
+            model: model.to_string(),
+        }
+    }
+
+    fn completion(&self, content: &str, usage: Option<&Usage>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+            "usage": usage,
+        })
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    }
+
+    fn usage_chunk(&self, usage: &Usage) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [],
+            "usage": usage,
+        })
+    }
+}
+
+#[post("/chat/completions")]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(name = "chat_completions", skip_all, fields(model = tracing::field::Empty, stream = tracing::field::Empty))]
+pub async fn chat_completions(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    cache: web::Data<Arc<AppCache>>,
+    models: web::Data<Arc<ModelRegistry>>,
+    quotas: web::Data<Arc<QuotaTracker>>,
+    audit: web::Data<Arc<AuditLog>>,
+    redactor: web::Data<Arc<Redactor>>,
+    client: web::ReqData<ClientId>,
+    principal: Option<web::ReqData<Principal>>,
+    payload: web::Json<ChatCompletionRequest>,
+) -> Result<HttpResponse, OpenAiError> {
+    let started = Instant::now();
+    let mut request = payload.into_inner();
+    let redactions = redactor.redact_values(&mut request.messages);
+    let messages = request.text_messages();
+    let model = models.route(request.model.as_deref().filter(|m| !m.is_empty()), conversation::estimate_tokens(&messages), None)?;
+    auth::check_model(principal.as_deref(), &model.spec.name)?;
+    request.validate(&config.generation_limits)?;
+    let prompt_tokens = conversation::estimate_tokens(&messages);
+    if prompt_tokens > model.spec.context_size {
+        return Err(ApiError::InvalidRequest(format!(
+            "Messages take about {} tokens, more than the {} of the model's context",
+            prompt_tokens, model.spec.context_size
+        )).into());
+    }
+    request.model = Some(model.spec.name.clone());
+    tracing::Span::current().record("model", model.spec.name.as_str()).record("stream", request.stream);
+
+    let reservation = quotas.check(&client, model.cost(conversation::estimate_tokens(&messages) as u64)).map_err(ApiError::QuotaExceeded)?;
+    let body = serde_json::to_value(&request).map_err(|e| ApiError::InvalidRequest(e.to_string()))?;
+    let client = client.into_inner();
+    let principal_name = principal.map(|p| p.into_inner().name);
+    if request.stream {
+        let audit = audit.get_ref().clone();
+        let idle = Duration::from_secs(config.llm_stream_timeout_secs);
+        return stream_completion(model, reservation, audit, client, principal_name, request, body, messages, redactions, idle, started).await;
+    }
+
+    let cache_mode = cache_mode(&req);
+    let cache_key = cache::body_key(&body);
+    if cache_mode.reads() {
+        if let Some(cached) = cache.get(&cache_key) {
+            // Stored with placeholders, as the backend answered
+            let mut completion = serde_json::from_str::<Value>(&cached).unwrap_or_default();
+            restore_completion(&mut completion, &redactions);
+            audit.record(AuditRecord {
+                prompt: &redactions.reveal(latest_prompt(&messages)),
+                response: completion["choices"][0]["message"]["content"].as_str().unwrap_or_default(),
+                cached: true,
+                ..AuditRecord::new("/v1/chat/completions", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+            });
+            let body = match redactions.restores() {
+                true => completion.to_string(),
+                false => cached,
+            };
+            return Ok(report_redactions(&mut HttpResponse::Ok(), &redactions)
+                .insert_header((cache::X_CACHE, "HIT"))
+                .content_type("application/json")
+                .body(body));
+        }
+    }
+    let mut completion = match model.provider.forward_openai(&body).await? {
+        Some(resp) => resp.json::<Value>().await.map_err(ProviderError::from)?,
+        None => {
+            let completion = model.provider.chat(&request.plain_messages()?, &request.params).await?;
+            CompletionMeta::new(&model.spec.name).completion(&completion.content, completion.usage.as_ref())
+        }
+    };
+    let usage = serde_json::from_value::<Usage>(completion["usage"].clone()).ok();
+    let content = completion["choices"][0]["message"]["content"].as_str().unwrap_or_default();
+    reservation.settle(model.charge(usage.as_ref(), &messages, content));
+    if cache_mode.writes() {
+        cache.set(cache_key, completion.to_string());
+    }
+    restore_completion(&mut completion, &redactions);
+    audit.record(AuditRecord {
+        prompt: &redactions.reveal(latest_prompt(&messages)),
+        response: completion["choices"][0]["message"]["content"].as_str().unwrap_or_default(),
+        usage: usage.as_ref(),
+        ..AuditRecord::new("/v1/chat/completions", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+    });
+    Ok(report_redactions(&mut HttpResponse::Ok(), &redactions).insert_header((cache::X_CACHE, "MISS")).json(completion))
+}
+
+/// Streams a completion as `chat.completion.chunk` events. OpenAI backends'
+/// streams are relayed byte for byte; others are re-encoded from provider
+/// events, and relayed streams are re-encoded too when redacted values have
+/// to be restored. Streams bypass the response cache.
+#[allow(clippy::too_many_arguments)]
+async fn stream_completion(
+    model: Arc<Model>,
+    reservation: Reservation,
+    audit: Arc<AuditLog>,
+    client: ClientId,
+    principal_name: Option<String>,
+    request: ChatCompletionRequest,
+    body: Value,
+    messages: Vec<ChatMessage>,
+    redactions: Redactions,
+    idle: Duration,
+    started: Instant,
+) -> Result<HttpResponse, OpenAiError> {
+    let mut resp = sse::response();
+    report_redactions(&mut resp, &redactions);
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(32);
+    let request_id = telemetry::current_request_id();
+    match model.provider.forward_openai(&body).await? {
+        Some(resp) => {
+            actix_web::rt::spawn(async move {
+                let mut upstream = providers::idle_chunks(resp, idle);
+                let mut decoder = LineDecoder::default();
+                let mut content = String::new();
+                let mut answer = String::new();
+                let mut restorer = redactions.restorer();
+                let mut tool_restorers = BTreeMap::new();
+                let mut usage = None;
+                // Watch the chunks go by to charge the right amount afterwards,
+                // rewriting them when values have to be restored
+                let mut relay = |line: String, rewritten: &mut String| {
+                    let Some(mut chunk) = sse::data_payload(&line).and_then(|d| serde_json::from_str::<Value>(d).ok()) else {
+                        rewritten.push_str(&line);
+                        rewritten.push('\n');
+                        return;
+                    };
+                    let delta = chunk["choices"][0]["delta"]["content"].as_str().unwrap_or_default().to_string();
+                    content.push_str(&delta);
+                    let mut text = restorer.push(&delta);
+                    restore_tool_call_deltas(&mut chunk["choices"][0]["delta"], &mut tool_restorers, &redactions);
+                    if !chunk["choices"][0]["finish_reason"].is_null() {
+                        text.push_str(&restorer.finish());
+                        finish_tool_call_deltas(&mut chunk["choices"][0]["delta"], &mut tool_restorers);
+                    }
+                    answer.push_str(&text);
+                    if chunk["choices"][0]["delta"]["content"].is_string() || !text.is_empty() {
+                        chunk["choices"][0]["delta"]["content"] = Value::String(text);
+                    }
+                    if let Ok(u) = serde_json::from_value::<Usage>(chunk["usage"].clone()) {
+                        usage = Some(u);
+                    }
+                    rewritten.push_str(&format!("data: {}\n", chunk));
+                };
+                let mut interrupted = false;
+                while let Some(next) = upstream.next().await {
+                    let bytes = match next {
+                        Ok(bytes) => bytes,
+                        Err(error) => {
+                            log::warn!("Upstream stream for {} failed: {}", model.spec.name, error);
+                            let _ = tx.send(sse::data(&OpenAiError::from(error).to_json())).await;
+                            interrupted = true;
+                            break;
+                        }
+                    };
+                    let mut rewritten = String::new();
+                    for line in decoder.push(&bytes) {
+                        relay(line, &mut rewritten);
+                    }
+                    let bytes = match redactions.restores() {
+                        true => Bytes::from(rewritten),
+                        false => bytes,
+                    };
+                    if tx.send(bytes).await.is_err() {
+                        interrupted = true;
+                        break;
+                    }
+                }
+                // A last event the upstream didn't end with a newline; passed
+                // through as is it has already gone out
+                if let Some(line) = decoder.finish() {
+                    let mut rewritten = String::new();
+                    relay(line, &mut rewritten);
+                    if !interrupted && redactions.restores() {
+                        let _ = tx.send(Bytes::from(rewritten + "\n")).await;
+                    }
+                }
+                reservation.settle(model.charge(usage.as_ref(), &messages, &content));
+                answer.push_str(&restorer.finish());
+                audit.record(AuditRecord {
+                    request_id,
+                    prompt: &redactions.reveal(latest_prompt(&messages)),
+                    response: &answer,
+                    usage: usage.as_ref(),
+                    ..AuditRecord::new("/v1/chat/completions", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+                });
+            }.in_current_span());
+        }
+        None => {
+            let mut upstream = model.provider.chat_stream(&request.plain_messages()?, &request.params).await?;
+            let meta = CompletionMeta::new(&model.spec.name);
+            let include_usage = request.include_usage();
+            actix_web::rt::spawn(async move {
+                let mut content = String::new();
+                let mut answer = String::new();
+                let mut restorer = redactions.restorer();
+                let mut usage = None;
+                let mut interrupted = tx.send(sse::data(&meta.chunk(json!({"role": "assistant"}), None))).await.is_err();
+                while !interrupted {
+                    match upstream.next().await {
+                        Some(Ok(StreamEvent::Token(token))) => {
+                            content.push_str(&token);
+                            let text = restorer.push(&token);
+                            if !text.is_empty() {
+                                answer.push_str(&text);
+                                interrupted = tx.send(sse::data(&meta.chunk(json!({"content": text}), None))).await.is_err();
+                            }
+                        }
+                        Some(Ok(StreamEvent::Usage(u))) => usage = Some(u),
+                        Some(Ok(StreamEvent::Done)) | None => break,
+                        Some(Err(e)) => {
+                            let _ = tx.send(sse::data(&OpenAiError::from(e).to_json())).await;
+                            interrupted = true;
+                        }
+                    }
+                }
+                reservation.settle(model.charge(usage.as_ref(), &messages, &content));
+                let rest = restorer.finish();
+                answer.push_str(&rest);
+                audit.record(AuditRecord {
+                    request_id,
+                    prompt: &redactions.reveal(latest_prompt(&messages)),
+                    response: &answer,
+                    usage: usage.as_ref(),
+                    ..AuditRecord::new("/v1/chat/completions", client.as_str(), principal_name.as_deref(), &model.spec.name, started)
+                });
+                if interrupted {
+                    return;
+                }
+                let mut tail = Vec::new();
+                if !rest.is_empty() {
+                    tail.push(sse::data(&meta.chunk(json!({"content": rest}), None)));
+                }
+                tail.push(sse::data(&meta.chunk(json!({}), Some("stop"))));
+                if let Some(usage) = usage.as_ref().filter(|_| include_usage) {
+                    tail.push(sse::data(&meta.usage_chunk(usage)));
+                }
+                tail.push(Bytes::from_static(sse::DONE));
+                for bytes in tail {
+                    if tx.send(bytes).await.is_err() {
+                        return;
+                    }
+                }
+            }.in_current_span());
+        }
+    }
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|b| (Ok::<_, actix_web::Error>(b), rx))
+    });
+    Ok(resp.insert_header((cache::X_CACHE, "MISS")).streaming(events))
+}
+
+#[get("/models")]
+pub async fn list_models(
+    models: web::Data<Arc<ModelRegistry>>,
+    principal: Option<web::ReqData<Principal>>,
+) -> impl Responder {
+    let data: Vec<Value> = models.models().iter()
+        .map(|m| &m.spec.name)
+        .filter(|m| principal.as_deref().is_none_or(|p| p.allows_model(m)))
+        .map(|m| json!({"id": m, "object": "model", "created": 0, "owned_by": "hello-genai"}))
+        .collect();
+    HttpResponse::Ok().json(json!({"object": "list", "data": data}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(messages: Value) -> ChatCompletionRequest {
+        serde_json::from_value(json!({"model": "m", "messages": messages})).unwrap()
+    }
+
+    fn redacted(text: &str) -> Redactions {
+        let mut messages = vec![json!({"role": "user", "content": text})];
+        Redactor::builtin(true).redact_values(&mut messages)
+    }
+
+    #[test]
+    fn limits_message_count_and_user_message_size() {
+        let wide: crate::params::Bounds = "-10..10".parse().unwrap();
+        let limits = GenerationLimits { temperature: wide, top_p: wide, penalty: wide, max_tokens: 100, max_stop_sequences: 4 };
+        assert!(request(json!([{"role": "user", "content": "hi"}])).validate(&limits).is_ok());
+        assert!(request(json!([])).validate(&limits).is_err());
+        assert!(request(json!([{"content": "hi"}])).validate(&limits).is_err());
+        let long = "x".repeat(conversation::MAX_MESSAGE_LEN + 1);
+        assert!(request(json!([{"role": "user", "content": long}])).validate(&limits).is_err());
+        assert!(request(json!([{"role": "user", "content": [{"type": "text", "text": long}]}])).validate(&limits).is_err());
+        // Earlier answers may be longer than what a user can send
+        assert!(request(json!([{"role": "assistant", "content": long}, {"role": "user", "content": "more"}])).validate(&limits).is_ok());
+        let many: Vec<Value> = (0..=MAX_MESSAGES).map(|_| json!({"role": "user", "content": "hi"})).collect();
+        assert!(request(Value::Array(many)).validate(&limits).is_err());
+    }
+
+    #[test]
+    fn restores_content_and_tool_call_arguments() {
+        let redactions = redacted("mail jane@example.org");
+        let mut completion = json!({"choices": [{"message": {
+            "content": "Mailing [EMAIL_1]",
+            "tool_calls": [{"index": 0, "function": {"name": "send", "arguments": "{\"to\": \"[EMAIL_1]\"}"}}],
+        }}]});
+        restore_completion(&mut completion, &redactions);
+        assert_eq!(completion["choices"][0]["message"]["content"], "Mailing jane@example.org");
+        assert_eq!(completion["choices"][0]["message"]["tool_calls"][0]["function"]["arguments"], "{\"to\": \"jane@example.org\"}");
+    }
+
+    #[test]
+    fn restores_tool_call_arguments_split_across_chunks() {
+        let redactions = redacted("mail jane@example.org and 10.0.0.1");
+        let mut restorers = BTreeMap::new();
+        let mut sent = BTreeMap::<u64, String>::new();
+        let fragments = [(0, "{\"to\": \"[EMA"), (1, "{\"ip\": \"[IP_ADDRESS_1"), (0, "IL_1]\"}"), (1, "]\"}")];
+        for (index, fragment) in fragments {
+            let mut delta = json!({"tool_calls": [{"index": index, "function": {"arguments": fragment}}]});
+            restore_tool_call_deltas(&mut delta, &mut restorers, &redactions);
+            sent.entry(index).or_default().push_str(delta["tool_calls"][0]["function"]["arguments"].as_str().unwrap());
+        }
+        let mut last = json!({});
+        finish_tool_call_deltas(&mut last, &mut restorers);
+        assert!(last["tool_calls"].is_null());
+        assert_eq!(sent[&0], "{\"to\": \"jane@example.org\"}");
+        assert_eq!(sent[&1], "{\"ip\": \"10.0.0.1\"}");
+    }
+
+    #[test]
+    fn flushes_held_back_tool_call_arguments_at_the_end() {
+        let redactions = redacted("mail jane@example.org");
+        let mut restorers = BTreeMap::new();
+        let mut delta = json!({"tool_calls": [{"index": 2, "function": {"arguments": "see [EMAIL"}}]});
+        restore_tool_call_deltas(&mut delta, &mut restorers, &redactions);
+        assert_eq!(delta["tool_calls"][0]["function"]["arguments"], "see ");
+        let mut last = json!({"content": null});
+        finish_tool_call_deltas(&mut last, &mut restorers);
+        assert_eq!(last["tool_calls"], json!([{"index": 2, "function": {"arguments": "[EMAIL"}}]));
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+
+/// Inclusive range for a float setting, written as `min..max` in config.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Bounds {
+    fn check(&self, name: &str, value: Option<f64>) -> Result<(), String> {
+        match value {
+            Some(v) if !(self.min..=self.max).contains(&v) => {
+                Err(format!("{} must be between {} and {}", name, self.min, self.max))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Bounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s.split_once("..").ok_or_else(|| format!("range '{}' must look like 0..2", s))?;
+        let min: f64 = min.trim().parse().map_err(|_| format!("bad minimum in range '{}'", s))?;
+        let max: f64 = max.trim().parse().map_err(|_| format!("bad maximum in range '{}'", s))?;
+        if min > max {
+            return Err(format!("range '{}' is empty", s));
+        }
+        Ok(Bounds { min, max })
+    }
+}
+
+/// Server-side limits on what clients may ask for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GenerationLimits {
+    pub temperature: Bounds,
+    pub top_p: Bounds,
+    /// Applies to both presence and frequency penalties.
+    pub penalty: Bounds,
+    pub max_tokens: u32,
+    pub max_stop_sequences: usize,
+}
+
+/// Sampling settings a client may set per request. Unset fields are left to
+/// the backend's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+}
+
+impl GenerationParams {
+    /// These settings, with any unset field taken from `defaults`.
+    pub fn with_defaults(&self, defaults: &GenerationParams) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            stop: self.stop.clone().or_else(|| defaults.stop.clone()),
+            seed: self.seed.or(defaults.seed),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+        }
+    }
+
+    pub fn validate(&self, limits: &GenerationLimits) -> Result<(), String> {
+        limits.temperature.check("temperature", self.temperature)?;
+        limits.top_p.check("top_p", self.top_p)?;
+        limits.penalty.check("presence_penalty", self.presence_penalty)?;
+        limits.penalty.check("frequency_penalty", self.frequency_penalty)?;
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens == 0 || max_tokens > limits.max_tokens {
+                return Err(format!("max_tokens must be between 1 and {}", limits.max_tokens));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > limits.max_stop_sequences || stop.iter().any(String::is_empty) {
+                return Err(format!("stop takes up to {} non-empty sequences", limits.max_stop_sequences));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accepts `"stop": "x"` as well as `"stop": ["x", "y"]`, as OpenAI does.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(s)) => Some(vec![s]),
+        Some(OneOrMany::Many(v)) => Some(v),
+        None => None,
+    })
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::params::{GenerationLimits, GenerationParams};
+use crate::watch;
+
+/// Name of the persona that is always available, even without a prompts directory.
+pub const BUILTIN_PERSONA: &str = "default";
+
+const BUILTIN_PROMPT: &str = "You are a helpful assistant. Please provide structured responses using markdown formatting. Use headers (# for main points), bullet points (- for lists), bold (**text**) for emphasis, and code blocks (```code```) for code examples. Organize your responses with clear sections and concise explanations.";
+
+/// A named system prompt with default generation settings, loaded from
+/// `<name>.md` in the prompts directory.
+#[derive(Clone, Debug, Serialize)]
+pub struct Persona {
+    pub name: String,
+    pub description: String,
+    #[serde(skip)]
+    pub prompt: String,
+    /// Defaults for requests using this persona; request fields win.
+    pub params: GenerationParams,
+}
+
+// `deny_unknown_fields` doesn't work alongside `flatten`, so whatever
+// `params` leaves over is collected here and rejected instead
+#[derive(Default, Deserialize)]
+struct FrontMatter {
+    #[serde(default)]
+    description: String,
+    #[serde(flatten)]
+    params: GenerationParams,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl Persona {
+    fn builtin() -> Self {
+        Persona {
+            name: BUILTIN_PERSONA.to_string(),
+            description: "General-purpose assistant answering in structured markdown".to_string(),
+            prompt: BUILTIN_PROMPT.to_string(),
+            params: GenerationParams::default(),
+        }
+    }
+
+    /// Parses a persona file: optional YAML front-matter between `---`
+    /// lines, then the prompt itself.
+    fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let (front, prompt) = match text.strip_prefix("---") {
+            Some(rest) => rest.split_once("\n---").ok_or("front-matter is missing its closing ---")?,
+            None => ("", text),
+        };
+        let front: FrontMatter = if front.trim().is_empty() {
+            FrontMatter::default()
+        } else {
+            serde_yaml::from_str(front).map_err(|e| e.to_string())?
+        };
+        if !front.unknown.is_empty() {
+            let fields: Vec<&str> = front.unknown.keys().map(String::as_str).collect();
+            return Err(format!("unknown front-matter fields: {}", fields.join(", ")));
+        }
+        let prompt = prompt.trim().to_string();
+        if prompt.is_empty() {
+            return Err("prompt is empty".to_string());
+        }
+        Ok(Persona { name: name.to_string(), description: front.description, prompt, params: front.params })
+    }
+}
+
+/// Personas loaded from a directory of markdown files, reloaded whenever the
+/// directory changes. Files that fail to parse are skipped with a warning.
+pub struct PersonaStore {
+    dir: PathBuf,
+    limits: GenerationLimits,
+    personas: RwLock<HashMap<String, Persona>>,
+}
+
+impl PersonaStore {
+    pub fn new(dir: impl Into<PathBuf>, limits: GenerationLimits) -> Self {
+        let store = PersonaStore { dir: dir.into(), limits, personas: RwLock::new(HashMap::new()) };
+        store.reload();
+        store
+    }
+
+    pub fn reload(&self) {
+        let mut personas = HashMap::from([(BUILTIN_PERSONA.to_string(), Persona::builtin())]);
+        match std::fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for path in entries.flatten().map(|e| e.path()) {
+                    if path.extension().is_some_and(|ext| ext == "md") {
+                        if let Some(persona) = self.load(&path) {
+                            personas.insert(persona.name.clone(), persona);
+                        }
+                    }
+                }
+            }
+            Err(e) => log::warn!("Cannot read prompts directory {}: {}", self.dir.display(), e),
+        }
+        log::info!("Loaded {} personas from {}", personas.len(), self.dir.display());
+        *self.personas.write().unwrap() = personas;
+    }
+
+    fn load(&self, path: &Path) -> Option<Persona> {
+        let name = path.file_stem()?.to_string_lossy().to_string();
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Persona::parse(&name, &text))
+            .and_then(|p| p.params.validate(&self.limits).map(|_| p));
+        parsed.map_err(|e| log::warn!("Skipping persona {}: {}", path.display(), e)).ok()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Persona> {
+        self.personas.read().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Persona> {
+        let mut personas: Vec<Persona> = self.personas.read().unwrap().values().cloned().collect();
+        personas.sort_by(|a, b| a.name.cmp(&b.name));
+        personas
+    }
+
+    /// Reloads the personas whenever the directory changes, checking every `every`.
+    pub fn spawn_watcher(self: &Arc<Self>, every: Duration) {
+        let store = Arc::clone(self);
+        watch::spawn_poller(self.dir.clone(), every, move || store.reload());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_front_matter_and_prompt() {
+        let persona = Persona::parse("terse", "---\ndescription: Short\ntemperature: 0.3\nstop: END\n---\n\nBe brief.\n").unwrap();
+        assert_eq!(persona.name, "terse");
+        assert_eq!(persona.description, "Short");
+        assert_eq!(persona.prompt, "Be brief.");
+        assert_eq!(persona.params.temperature, Some(0.3));
+        assert_eq!(persona.params.stop, Some(vec!["END".to_string()]));
+    }
+
+    #[test]
+    fn front_matter_is_optional() {
+        let persona = Persona::parse("plain", "Be brief.").unwrap();
+        assert_eq!(persona.description, "");
+        assert_eq!(persona.prompt, "Be brief.");
+        assert!(persona.params.temperature.is_none());
+        assert_eq!(Persona::parse("empty", "---\n---\nBe brief.").unwrap().prompt, "Be brief.");
+    }
+
+    #[test]
+    fn rejects_unknown_front_matter_fields() {
+        let err = Persona::parse("typo", "---\ntemprature: 0.3\nmodel: x\n---\nBe brief.").unwrap_err();
+        assert_eq!(err, "unknown front-matter fields: model, temprature");
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(Persona::parse("open", "---\ndescription: x\nBe brief.").is_err());
+        assert!(Persona::parse("blank", "---\ndescription: x\n---\n  \n").is_err());
+        assert!(Persona::parse("bad", "---\ntemperature: warm\n---\nBe brief.").is_err());
+    }
+}
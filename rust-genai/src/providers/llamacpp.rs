@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use super::{get_json, line_stream, params_object, post_json, post_stream, read_field, Completion, EventStream, LlmProvider, ProviderError, StreamEvent, Timeouts, Usage};
+use crate::conversation::ChatMessage;
+use crate::params::GenerationParams;
+use crate::sse;
+
+/// llama.cpp server's raw `/completion` endpoint. It takes a single prompt
+/// string, so the conversation is flattened into a plain transcript.
+pub struct LlamaCppProvider {
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    base_url: String,
+    /// Name the served model is known by here; the server ignores it.
+    model: String,
+}
+
+impl LlamaCppProvider {
+    pub fn new(client: reqwest::Client, timeouts: Timeouts, base_url: String, model: String) -> Self {
+        LlamaCppProvider { client, timeouts, base_url, model }
+    }
+
+    fn url(&self) -> String {
+        format!("{}/completion", self.base_url)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LlamaCppProvider {
+    async fn chat(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<Completion, ProviderError> {
+        let body = request_body(messages, params, false);
+        let resp = post_json(&self.client, &self.url(), &body, self.timeouts.request).await?;
+        let (content, v) = read_field(resp, "/content").await?;
+        Ok(Completion {
+            content: content.trim_start().to_string(),
+            usage: parse_usage(&v),
+        })
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<EventStream, ProviderError> {
+        let body = request_body(messages, params, true);
+        let resp = post_stream(&self.client, &self.url(), &body, self.timeouts).await?;
+        Ok(line_stream(resp, self.timeouts.stream_idle, |line| sse::data_payload(line).map(parse_chunk).unwrap_or_default()))
+    }
+
+    /// A llama.cpp server runs the one model it was started with; asking for
+    /// its model list just confirms it is up.
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        get_json(&self.client, &format!("{}/v1/models", self.base_url), self.timeouts.request).await?;
+        Ok(vec![self.model.clone()])
+    }
+}
+
+fn request_body(messages: &[ChatMessage], params: &GenerationParams, stream: bool) -> Value {
+    let mut options = params_object(params);
+    // The transcript's own turn markers always stop generation
+    let mut stop = vec!["\nUser:".to_string(), "\nSystem:".to_string()];
+    stop.extend(params.stop.iter().flatten().cloned());
+    options.remove("stop");
+    if let Some(max_tokens) = options.remove("max_tokens") {
+        options.insert("n_predict".to_string(), max_tokens);
+    }
+    let mut body = json!({
+        "prompt": transcript(messages),
+        "stream": stream,
+        "stop": stop,
+        "cache_prompt": true
+    });
+    if let Value::Object(fields) = &mut body {
+        fields.extend(options);
+    }
+    body
+}
+
+fn transcript(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::new();
+    for m in messages {
+        let role = match m.role.as_str() {
+            "system" => "System",
+            "assistant" => "Assistant",
+            _ => "User",
+        };
+        prompt.push_str(&format!("{}: {}\n", role, m.content));
+    }
+    prompt.push_str("Assistant:");
+    prompt
+}
+
+fn parse_chunk(data: &str) -> Vec<StreamEvent> {
+    let v: Value = match serde_json::from_str(data) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut events = Vec::new();
+    if let Some(token) = v["content"].as_str() {
+        if !token.is_empty() {
+            events.push(StreamEvent::Token(token.to_string()));
+        }
+    }
+    if v["stop"].as_bool() == Some(true) {
+        if let Some(usage) = parse_usage(&v) {
+            events.push(StreamEvent::Usage(usage));
+        }
+        events.push(StreamEvent::Done);
+    }
+    events
+}
+
+fn parse_usage(v: &Value) -> Option<Usage> {
+    let prompt = v["tokens_evaluated"].as_u64()?;
+    let completion = v["tokens_predicted"].as_u64().unwrap_or(0);
+    Some(Usage::new(prompt, completion))
+}
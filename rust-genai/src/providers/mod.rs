@@ -0,0 +1,355 @@
+mod llamacpp;
+mod ollama;
+mod openai;
+
+pub use llamacpp::LlamaCppProvider;
+pub use ollama::OllamaProvider;
+pub use openai::OpenAiProvider;
+
+use async_trait::async_trait;
+use actix_web::web::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{AppConfig, ProviderKind};
+use crate::conversation::ChatMessage;
+use crate::params::GenerationParams;
+use crate::sse::LineDecoder;
+use crate::config::redact_url;
+use crate::telemetry;
+use tracing::Instrument;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Usage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+    }
+}
+
+pub struct Completion {
+    pub content: String,
+    pub usage: Option<Usage>,
+}
+
+/// One step of a streamed completion.
+pub enum StreamEvent {
+    Token(String),
+    Usage(Usage),
+    Done,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// The upstream did not answer in time.
+    Timeout,
+    /// The upstream could not be connected to, or the connection dropped.
+    Unreachable(String),
+    /// The upstream answered with a non-2xx status, possibly saying via
+    /// `Retry-After` when to try again.
+    Status { status: reqwest::StatusCode, message: String, retry_after: Option<Duration> },
+    /// The upstream answered 2xx but the body could not be understood.
+    InvalidResponse(String),
+}
+
+impl ProviderError {
+    /// Error text reported by the upstream, taken from the usual
+    /// `{"error": {"message": ...}}` / `{"error": "..."}` shapes when present.
+    pub fn upstream_message(&self) -> &str {
+        match self {
+            ProviderError::Status { message, .. } => message,
+            ProviderError::Unreachable(msg) | ProviderError::InvalidResponse(msg) => msg,
+            ProviderError::Timeout => "",
+        }
+    }
+
+    /// Whether a 4xx was caused by the prompt not fitting the model's context window.
+    /// Runtimes word this differently, so match on the common phrasings.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        match self {
+            ProviderError::Status { status, message, .. } if status.is_client_error() => {
+                let m = message.to_ascii_lowercase();
+                ["context_length_exceeded", "context length", "context window", "context size", "n_ctx", "too many tokens"]
+                    .iter()
+                    .any(|needle| m.contains(needle))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ProviderError::Timeout
+        } else if e.is_decode() {
+            ProviderError::InvalidResponse(e.to_string())
+        } else {
+            ProviderError::Unreachable(e.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Timeout => write!(f, "request timed out"),
+            ProviderError::Unreachable(e) => write!(f, "request failed: {}", e),
+            ProviderError::Status { status, message, .. } => write!(f, "upstream returned {}: {}", status, message),
+            ProviderError::InvalidResponse(e) => write!(f, "invalid upstream response: {}", e),
+        }
+    }
+}
+
+pub type EventStream = BoxStream<'static, Result<StreamEvent, ProviderError>>;
+
+/// A chat backend. Implementations translate the conversation into the
+/// runtime's wire format and normalize its replies.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn chat(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<Completion, ProviderError>;
+
+    /// Starts a streamed completion. Dropping the stream aborts the upstream request.
+    async fn chat_stream(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<EventStream, ProviderError>;
+
+    /// Sends an OpenAI `/chat/completions` body unchanged, for backends that
+    /// speak that API natively. Others return `None` and are driven through
+    /// `chat`/`chat_stream` instead, without the fields those can't express.
+    async fn forward_openai(&self, _body: &serde_json::Value) -> Result<Option<reqwest::Response>, ProviderError> {
+        Ok(None)
+    }
+
+    /// Models the backend currently serves, under the names requests use.
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError>;
+}
+
+/// How long upstream calls may take. Streamed replies have no overall
+/// limit, as a long answer can take minutes; they fail instead once the
+/// backend goes quiet for `stream_idle`.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// A whole non-streamed request, or a streamed one until its headers arrive.
+    pub request: Duration,
+    /// The longest gap between chunks of a streamed reply.
+    pub stream_idle: Duration,
+}
+
+impl Timeouts {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Timeouts {
+            request: Duration::from_secs(config.llm_timeout_secs),
+            stream_idle: Duration::from_secs(config.llm_stream_timeout_secs),
+        }
+    }
+}
+
+/// The HTTP client shared by every provider, pooling connections to the
+/// backends. Request timeouts are set per call from `Timeouts`.
+pub fn http_client(config: &AppConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.llm_connect_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(config.llm_idle_timeout_secs))
+        .build()
+        .expect("HTTP client configuration is valid")
+}
+
+pub fn build(kind: ProviderKind, client: reqwest::Client, timeouts: Timeouts, base_url: &str, model: &str) -> Arc<dyn LlmProvider> {
+    let base_url = base_url.trim_end_matches('/').to_string();
+    let model = model.to_string();
+    match kind {
+        ProviderKind::OpenAi => Arc::new(OpenAiProvider::new(client, timeouts, base_url, model)),
+        ProviderKind::Ollama => Arc::new(OllamaProvider::new(client, timeouts, base_url, model)),
+        ProviderKind::LlamaCpp => Arc::new(LlamaCppProvider::new(client, timeouts, base_url, model)),
+    }
+}
+
+/// Generation settings as a JSON object using OpenAI's field names, for
+/// providers to merge into their request bodies.
+fn params_object(params: &GenerationParams) -> serde_json::Map<String, serde_json::Value> {
+    match serde_json::to_value(params) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// Sends a request in a client span, carrying the trace and request id upstream.
+async fn send(req: reqwest::RequestBuilder, method: &str, url: &str) -> Result<reqwest::Response, ProviderError> {
+    let span = tracing::info_span!(
+        "llm.request",
+        otel.name = %format!("{} {}", method, url.rsplit('/').next().unwrap_or_default()),
+        otel.kind = "client",
+        http.method = method,
+        http.url = %redact_url(url),
+        http.status_code = tracing::field::Empty,
+    );
+    let headers = span.in_scope(telemetry::outgoing_headers);
+    let resp = req.headers(headers).send().instrument(span.clone()).await?;
+    span.record("http.status_code", resp.status().as_u16());
+    Ok(resp)
+}
+
+/// Sends a JSON body and fails on non-2xx statuses, keeping the upstream's
+/// error text. `timeout` bounds the whole exchange, body included.
+async fn post_json(
+    client: &reqwest::Client,
+    url: &str,
+    body: &serde_json::Value,
+    timeout: Duration,
+) -> Result<reqwest::Response, ProviderError> {
+    let resp = send(client.post(url).json(body).timeout(timeout), "POST", url).await?;
+    check_status(resp).await
+}
+
+/// Like `post_json` for a streamed reply: only waiting for the headers is
+/// bounded, the body is read under `idle_chunks`.
+async fn post_stream(
+    client: &reqwest::Client,
+    url: &str,
+    body: &serde_json::Value,
+    timeouts: Timeouts,
+) -> Result<reqwest::Response, ProviderError> {
+    let resp = actix_web::rt::time::timeout(timeouts.request, send(client.post(url).json(body), "POST", url))
+        .await
+        .map_err(|_| ProviderError::Timeout)??;
+    check_status(resp).await
+}
+
+/// A streamed response body that fails with `ProviderError::Timeout` once
+/// no chunk has arrived for `idle`.
+pub fn idle_chunks(resp: reqwest::Response, idle: Duration) -> BoxStream<'static, Result<Bytes, ProviderError>> {
+    stream::unfold(Some(resp.bytes_stream()), move |body| async move {
+        let mut body = body?;
+        match actix_web::rt::time::timeout(idle, body.next()).await {
+            Ok(Some(chunk)) => Some((chunk.map_err(ProviderError::from), Some(body))),
+            Ok(None) => None,
+            // Nothing follows a timeout
+            Err(_) => Some((Err(ProviderError::Timeout), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Turns a non-2xx response into `ProviderError::Status`.
+async fn check_status(resp: reqwest::Response) -> Result<reqwest::Response, ProviderError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let text = resp.text().await.unwrap_or_default();
+    Err(ProviderError::Status { status, message: error_message(&text), retry_after })
+}
+
+/// `Retry-After` as either delay seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some((at.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or_default())
+}
+
+fn error_message(body: &str) -> String {
+    const MAX_LEN: usize = 500;
+    let v: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let message = v["error"]["message"].as_str()
+        .or_else(|| v["error"].as_str())
+        .or_else(|| v["message"].as_str())
+        .unwrap_or(body);
+    message.chars().take(MAX_LEN).collect()
+}
+
+/// Fetches a JSON document, failing on non-2xx statuses like `post_json`.
+async fn get_json(client: &reqwest::Client, url: &str, timeout: Duration) -> Result<serde_json::Value, ProviderError> {
+    let resp = send(client.get(url).timeout(timeout), "GET", url).await?;
+    Ok(check_status(resp).await?.json().await?)
+}
+
+/// The `field` of every object in the array at `pointer`, e.g. model ids.
+fn list_field(v: &serde_json::Value, pointer: &str, field: &str) -> Vec<String> {
+    v.pointer(pointer)
+        .and_then(|list| list.as_array())
+        .map(|list| list.iter().filter_map(|item| item[field].as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+/// Reads a JSON response body and pulls the string at `pointer` out of it.
+async fn read_field(resp: reqwest::Response, pointer: &str) -> Result<(String, serde_json::Value), ProviderError> {
+    let v: serde_json::Value = resp.json().await?;
+    match v.pointer(pointer).and_then(|c| c.as_str()) {
+        Some(content) => Ok((content.to_string(), v)),
+        None => Err(ProviderError::InvalidResponse(format!("missing {} in response", pointer))),
+    }
+}
+
+/// Turns a line-oriented streaming response into provider events, using
+/// `parse` to interpret each line.
+fn line_stream<F>(resp: reqwest::Response, idle: Duration, mut parse: F) -> EventStream
+where
+    F: FnMut(&str) -> Vec<StreamEvent> + Send + 'static,
+{
+    let mut decoder = LineDecoder::default();
+    // A trailing `None` marks end of body so the decoder can flush its last line
+    idle_chunks(resp, idle)
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |chunk| {
+            let events: Vec<Result<StreamEvent, ProviderError>> = match chunk {
+                Some(Ok(bytes)) => decoder.push(&bytes).iter().flat_map(|l| parse(l)).map(Ok).collect(),
+                Some(Err(e)) => vec![Err(e)],
+                None => decoder.finish().iter().flat_map(|l| parse(l)).map(Ok).collect(),
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+        let soon = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&soon).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+        assert_eq!(parse_retry_after("Thu, 01 Jan 1970 00:00:00 +0000"), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[actix_web::test]
+    async fn streams_fail_once_the_backend_goes_quiet() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        actix_web::rt::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut [0; 1024]).await;
+            socket.write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n3\r\nhi\n\r\n").await.unwrap();
+            // Stalls well past the idle timeout, without closing
+            actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+        });
+        let resp = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        let chunks: Vec<_> = idle_chunks(resp, Duration::from_millis(200)).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().ok().map(|b| &b[..]), Some(&b"hi\n"[..]));
+        assert!(matches!(chunks[1], Err(ProviderError::Timeout)));
+    }
+
+    #[test]
+    fn extracts_upstream_error_messages() {
+        assert_eq!(error_message(r#"{"error": {"message": "model not found"}}"#), "model not found");
+        assert_eq!(error_message(r#"{"error": "busy"}"#), "busy");
+        assert_eq!(error_message("plain text"), "plain text");
+        assert_eq!(error_message(&"x".repeat(600)).len(), 500);
+    }
+}
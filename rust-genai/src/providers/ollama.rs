@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use super::{get_json, line_stream, list_field, params_object, post_json, post_stream, read_field, Completion, EventStream, LlmProvider, ProviderError, StreamEvent, Timeouts, Usage};
+use crate::conversation::ChatMessage;
+use crate::params::GenerationParams;
+
+/// Ollama's native `/api/chat` endpoint, which streams newline-delimited JSON.
+pub struct OllamaProvider {
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    base_url: String,
+    model: String,
+}
+
+impl OllamaProvider {
+    pub fn new(client: reqwest::Client, timeouts: Timeouts, base_url: String, model: String) -> Self {
+        OllamaProvider { client, timeouts, base_url, model }
+    }
+
+    fn url(&self) -> String {
+        format!("{}/api/chat", self.base_url)
+    }
+
+    fn request_body(&self, messages: &[ChatMessage], params: &GenerationParams, stream: bool) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": stream
+        });
+        // Sampling settings go in `options`, where the token cap is `num_predict`
+        let mut options = params_object(params);
+        if let Some(max_tokens) = options.remove("max_tokens") {
+            options.insert("num_predict".to_string(), max_tokens);
+        }
+        if !options.is_empty() {
+            body["options"] = Value::Object(options);
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn chat(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<Completion, ProviderError> {
+        let body = self.request_body(messages, params, false);
+        let resp = post_json(&self.client, &self.url(), &body, self.timeouts.request).await?;
+        let (content, v) = read_field(resp, "/message/content").await?;
+        Ok(Completion {
+            content,
+            usage: parse_usage(&v),
+        })
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<EventStream, ProviderError> {
+        let body = self.request_body(messages, params, true);
+        let resp = post_stream(&self.client, &self.url(), &body, self.timeouts).await?;
+        Ok(line_stream(resp, self.timeouts.stream_idle, parse_line))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let v = get_json(&self.client, &format!("{}/api/tags", self.base_url), self.timeouts.request).await?;
+        // `llama3.2` and `llama3.2:latest` name the same model
+        let mut names = list_field(&v, "/models", "name");
+        let short: Vec<String> = names.iter().filter_map(|n| n.strip_suffix(":latest").map(String::from)).collect();
+        names.extend(short);
+        Ok(names)
+    }
+}
+
+fn parse_line(line: &str) -> Vec<StreamEvent> {
+    let v: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut events = Vec::new();
+    if let Some(token) = v["message"]["content"].as_str() {
+        if !token.is_empty() {
+            events.push(StreamEvent::Token(token.to_string()));
+        }
+    }
+    // The final object carries the token counts and `done: true`
+    if v["done"].as_bool() == Some(true) {
+        if let Some(usage) = parse_usage(&v) {
+            events.push(StreamEvent::Usage(usage));
+        }
+        events.push(StreamEvent::Done);
+    }
+    events
+}
+
+fn parse_usage(v: &Value) -> Option<Usage> {
+    let prompt = v["prompt_eval_count"].as_u64()?;
+    let completion = v["eval_count"].as_u64().unwrap_or(0);
+    Some(Usage::new(prompt, completion))
+}
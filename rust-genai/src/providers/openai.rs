@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use super::{get_json, line_stream, list_field, params_object, post_json, post_stream, read_field, Completion, EventStream, LlmProvider, ProviderError, StreamEvent, Timeouts, Usage};
+use crate::conversation::ChatMessage;
+use crate::params::GenerationParams;
+use crate::sse;
+
+/// OpenAI-compatible `/chat/completions` endpoint, as served by Docker Model
+/// Runner, vLLM, LM Studio and llama.cpp's server.
+pub struct OpenAiProvider {
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    base_url: String,
+    model: String,
+}
+
+impl OpenAiProvider {
+    pub fn new(client: reqwest::Client, timeouts: Timeouts, base_url: String, model: String) -> Self {
+        OpenAiProvider { client, timeouts, base_url, model }
+    }
+
+    fn url(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    fn request_body(&self, messages: &[ChatMessage], params: &GenerationParams, stream: bool) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": stream
+        });
+        if stream {
+            body["stream_options"] = json!({"include_usage": true});
+        }
+        if let Value::Object(fields) = &mut body {
+            fields.extend(params_object(params));
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn chat(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<Completion, ProviderError> {
+        let body = self.request_body(messages, params, false);
+        let resp = post_json(&self.client, &self.url(), &body, self.timeouts.request).await?;
+        let (content, v) = read_field(resp, "/choices/0/message/content").await?;
+        Ok(Completion {
+            content,
+            usage: parse_usage(&v["usage"]),
+        })
+    }
+
+    async fn chat_stream(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<EventStream, ProviderError> {
+        let body = self.request_body(messages, params, true);
+        let resp = post_stream(&self.client, &self.url(), &body, self.timeouts).await?;
+        Ok(line_stream(resp, self.timeouts.stream_idle, |line| sse::data_payload(line).map(parse_chunk).unwrap_or_default()))
+    }
+
+    async fn forward_openai(&self, body: &Value) -> Result<Option<reqwest::Response>, ProviderError> {
+        let mut body = body.clone();
+        body["model"] = self.model.clone().into();
+        let resp = match body["stream"].as_bool().unwrap_or_default() {
+            true => post_stream(&self.client, &self.url(), &body, self.timeouts).await?,
+            false => post_json(&self.client, &self.url(), &body, self.timeouts.request).await?,
+        };
+        Ok(Some(resp))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let v = get_json(&self.client, &format!("{}/models", self.base_url), self.timeouts.request).await?;
+        Ok(list_field(&v, "/data", "id"))
+    }
+}
+
+/// Interprets the payload of a `chat.completion.chunk` data line.
+fn parse_chunk(data: &str) -> Vec<StreamEvent> {
+    if data == "[DONE]" {
+        return vec![StreamEvent::Done];
+    }
+    let v: Value = match serde_json::from_str(data) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut events = Vec::new();
+    if let Some(token) = v["choices"][0]["delta"]["content"].as_str() {
+        if !token.is_empty() {
+            events.push(StreamEvent::Token(token.to_string()));
+        }
+    }
+    if let Some(usage) = parse_usage(&v["usage"]) {
+        events.push(StreamEvent::Usage(usage));
+    }
+    events
+}
+
+fn parse_usage(v: &Value) -> Option<Usage> {
+    let prompt = v["prompt_tokens"].as_u64()?;
+    let completion = v["completion_tokens"].as_u64().unwrap_or(0);
+    Some(Usage::new(prompt, completion))
+}
@@ -0,0 +1,348 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::client::ClientId;
+use crate::conversation::{self, ChatMessage};
+use crate::providers::Usage;
+
+/// Token allowances per UTC day and calendar month, written as
+/// `daily/monthly` in config. Zero means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuotaPolicy {
+    pub daily: u64,
+    pub monthly: u64,
+}
+
+impl QuotaPolicy {
+    fn limit(&self, period: Period) -> Option<u64> {
+        let limit = match period {
+            Period::Daily => self.daily,
+            Period::Monthly => self.monthly,
+        };
+        (limit > 0).then_some(limit)
+    }
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (daily, monthly) = s.split_once('/').ok_or_else(|| format!("token quota '{}' must look like 100000/2000000", s))?;
+        let daily = daily.trim().parse().map_err(|_| format!("bad daily tokens in quota '{}'", s))?;
+        let monthly = monthly.trim().parse().map_err(|_| format!("bad monthly tokens in quota '{}'", s))?;
+        Ok(QuotaPolicy { daily, monthly })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    fn start(self, now: DateTime<Utc>) -> NaiveDate {
+        let today = now.date_naive();
+        match self {
+            Period::Daily => today,
+            Period::Monthly => today.with_day(1).unwrap_or(today),
+        }
+    }
+
+    fn resets_at(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let start = self.start(now);
+        let next = match self {
+            Period::Daily => start.checked_add_days(Days::new(1)),
+            Period::Monthly => start.checked_add_months(Months::new(1)),
+        };
+        next.unwrap_or(start).and_time(chrono::NaiveTime::MIN).and_utc()
+    }
+}
+
+impl std::fmt::Display for Period {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Period::Daily => write!(f, "Daily"),
+            Period::Monthly => write!(f, "Monthly"),
+        }
+    }
+}
+
+/// Tokens a client has used in the current day and month.
+struct Tally {
+    day: NaiveDate,
+    day_tokens: u64,
+    month: NaiveDate,
+    month_tokens: u64,
+}
+
+impl Tally {
+    fn new(now: DateTime<Utc>) -> Self {
+        Tally {
+            day: Period::Daily.start(now),
+            day_tokens: 0,
+            month: Period::Monthly.start(now),
+            month_tokens: 0,
+        }
+    }
+
+    /// Starts afresh for any period that has ended since the last request.
+    fn roll(&mut self, now: DateTime<Utc>) {
+        if self.day != Period::Daily.start(now) {
+            self.day = Period::Daily.start(now);
+            self.day_tokens = 0;
+        }
+        if self.month != Period::Monthly.start(now) {
+            self.month = Period::Monthly.start(now);
+            self.month_tokens = 0;
+        }
+    }
+
+    fn used(&self, period: Period) -> u64 {
+        match period {
+            Period::Daily => self.day_tokens,
+            Period::Monthly => self.month_tokens,
+        }
+    }
+
+    fn add(&mut self, tokens: u64) {
+        self.day_tokens += tokens;
+        self.month_tokens += tokens;
+    }
+
+    /// Takes back a reservation from whichever of its periods are current.
+    fn release(&mut self, reservation: &Reservation) {
+        if self.day == reservation.day {
+            self.day_tokens = self.day_tokens.saturating_sub(reservation.tokens);
+        }
+        if self.month == reservation.month {
+            self.month_tokens = self.month_tokens.saturating_sub(reservation.tokens);
+        }
+    }
+}
+
+/// Tokens held against a client's quotas while its call runs, so parallel
+/// requests can't all pass the check on the same remaining budget. Settled
+/// with what the call used; dropped unsettled, e.g. when the call failed,
+/// it charges nothing.
+pub struct Reservation {
+    tracker: Arc<QuotaTracker>,
+    client: ClientId,
+    tokens: u64,
+    day: NaiveDate,
+    month: NaiveDate,
+    used: u64,
+}
+
+impl Reservation {
+    /// Charges `tokens` in place of the estimate.
+    pub fn settle(mut self, tokens: u64) {
+        self.used = tokens;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let now = Utc::now();
+        let mut tally = self.tracker.tallies.entry(self.client.clone()).or_insert_with(|| Tally::new(now));
+        tally.roll(now);
+        tally.release(self);
+        tally.add(self.used);
+    }
+}
+
+/// A request that would take a client past one of its token quotas.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QuotaExceeded {
+    pub period: Period,
+    pub limit: u64,
+    pub used: u64,
+    /// Seconds until the period resets.
+    #[serde(skip)]
+    pub retry_after: u64,
+}
+
+#[derive(Serialize)]
+pub struct PeriodUsage {
+    pub used: u64,
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub resets_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    pub client: String,
+    pub daily: PeriodUsage,
+    pub monthly: PeriodUsage,
+}
+
+/// Tokens used by a completed call: the upstream's count when it reports
+/// one, otherwise a local estimate of prompt and reply.
+pub fn usage_or_estimate(usage: Option<&Usage>, messages: &[ChatMessage], content: &str) -> Usage {
+    usage.cloned().unwrap_or_else(|| {
+        Usage::new(conversation::estimate_tokens(messages) as u64, conversation::estimate_text_tokens(content) as u64)
+    })
+}
+
+/// Tracks token consumption per client against daily and monthly budgets.
+/// Requests reserve an estimate of their prompt up front, which is replaced
+/// afterwards by the upstream's reported usage.
+pub struct QuotaTracker {
+    tallies: DashMap<ClientId, Tally>,
+    default_policy: QuotaPolicy,
+    key_policies: HashMap<ClientId, QuotaPolicy>,
+}
+
+impl QuotaTracker {
+    pub fn new(default_policy: QuotaPolicy, key_policies: HashMap<ClientId, QuotaPolicy>) -> Self {
+        QuotaTracker { tallies: DashMap::new(), default_policy, key_policies }
+    }
+
+    /// The quota that applies to a client: its key's own, or the default.
+    pub fn policy(&self, client: &ClientId) -> QuotaPolicy {
+        self.key_policies.get(client).copied().unwrap_or(self.default_policy)
+    }
+
+    /// Refuses the request if `estimate` more tokens would exceed a quota,
+    /// and otherwise reserves them under the same lock.
+    pub fn check(self: &Arc<Self>, client: &ClientId, estimate: u64) -> Result<Reservation, QuotaExceeded> {
+        let policy = self.policy(client);
+        let now = Utc::now();
+        let mut tally = self.tallies.entry(client.clone()).or_insert_with(|| Tally::new(now));
+        tally.roll(now);
+        for period in [Period::Daily, Period::Monthly] {
+            let Some(limit) = policy.limit(period) else { continue };
+            let used = tally.used(period);
+            if used + estimate > limit {
+                let retry_after = (period.resets_at(now) - now).num_seconds().max(1) as u64;
+                return Err(QuotaExceeded { period, limit, used, retry_after });
+            }
+        }
+        tally.add(estimate);
+        Ok(Reservation {
+            tracker: Arc::clone(self),
+            client: client.clone(),
+            tokens: estimate,
+            day: tally.day,
+            month: tally.month,
+            used: 0,
+        })
+    }
+
+    /// A client's usage, reported under `name` rather than its `ClientId`,
+    /// which for API keys is a hash the caller can't relate to.
+    pub fn usage(&self, client: &ClientId, name: &str) -> QuotaUsage {
+        let policy = self.policy(client);
+        let now = Utc::now();
+        let (day_tokens, month_tokens) = match self.tallies.get_mut(client) {
+            Some(mut tally) => {
+                tally.roll(now);
+                (tally.day_tokens, tally.month_tokens)
+            }
+            None => (0, 0),
+        };
+        let period_usage = |period: Period, used: u64| {
+            let limit = policy.limit(period);
+            PeriodUsage {
+                used,
+                limit,
+                remaining: limit.map(|l| l.saturating_sub(used)),
+                resets_at: period.resets_at(now),
+            }
+        };
+        QuotaUsage {
+            client: name.to_string(),
+            daily: period_usage(Period::Daily, day_tokens),
+            monthly: period_usage(Period::Monthly, month_tokens),
+        }
+    }
+
+    /// Drops tallies from previous months; a fresh one starts at zero anyway.
+    pub fn purge_stale(&self) {
+        let month = Period::Monthly.start(Utc::now());
+        self.tallies.retain(|_, t| t.month == month);
+    }
+
+    /// Runs `purge_stale` every `every` on the current runtime.
+    pub fn spawn_cleanup(self: &Arc<Self>, every: Duration) {
+        let tracker = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                tracker.purge_stale();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(daily: u64) -> Arc<QuotaTracker> {
+        let key = ClientId::for_key("ci");
+        Arc::new(QuotaTracker::new(QuotaPolicy { daily, monthly: 0 }, HashMap::from([(key, QuotaPolicy { daily: 0, monthly: 0 })])))
+    }
+
+    fn used_today(tracker: &QuotaTracker, client: &ClientId) -> u64 {
+        tracker.usage(client, "test").daily.used
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("100/2000".parse(), Ok(QuotaPolicy { daily: 100, monthly: 2000 }));
+        assert!("100".parse::<QuotaPolicy>().is_err());
+        assert!("x/1".parse::<QuotaPolicy>().is_err());
+    }
+
+    #[test]
+    fn reservations_count_against_parallel_checks() {
+        let tracker = tracker(100);
+        let client = ClientId::for_ip("10.0.0.1");
+        let first = tracker.check(&client, 60).unwrap();
+        let err = tracker.check(&client, 60).err().unwrap();
+        assert_eq!((err.period, err.limit, err.used), (Period::Daily, 100, 60));
+        drop(first);
+        assert!(tracker.check(&client, 60).is_ok());
+    }
+
+    #[test]
+    fn settling_replaces_the_estimate_with_actual_usage() {
+        let tracker = tracker(100);
+        let client = ClientId::for_ip("10.0.0.1");
+        tracker.check(&client, 30).unwrap().settle(45);
+        assert_eq!(used_today(&tracker, &client), 45);
+        let pending = tracker.check(&client, 10).unwrap();
+        assert_eq!(used_today(&tracker, &client), 55);
+        // A failed call charges nothing
+        drop(pending);
+        assert_eq!(used_today(&tracker, &client), 45);
+    }
+
+    #[test]
+    fn key_policies_override_the_default() {
+        let tracker = tracker(10);
+        let _held = tracker.check(&ClientId::for_key("ci"), 1_000).unwrap();
+        assert!(tracker.check(&ClientId::for_ip("10.0.0.1"), 1_000).is_err());
+        let usage = tracker.usage(&ClientId::for_key("ci"), "ci");
+        assert_eq!((usage.client.as_str(), usage.daily.limit, usage.daily.used), ("ci", None, 1_000));
+    }
+
+    #[test]
+    fn periods_reset_at_midnight_and_month_start() {
+        let now = DateTime::parse_from_rfc3339("2024-02-29T13:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(Period::Daily.resets_at(now).to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        assert_eq!(Period::Monthly.resets_at(now).to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        let mut tally = Tally::new(now);
+        tally.add(5);
+        tally.roll(now + chrono::Duration::days(1));
+        assert_eq!((tally.day_tokens, tally.month_tokens), (0, 0));
+    }
+}
@@ -1,33 +1,270 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::client::ClientId;
 
+/// `limit` requests per `window` seconds, written as `limit/window` in config.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RatePolicy {
+    pub limit: u32,
+    pub window: u64, // seconds
+}
+
+impl RatePolicy {
+    fn refill_per_sec(&self) -> f64 {
+        self.limit as f64 / self.window as f64
+    }
+}
+
+impl FromStr for RatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (limit, window) = s.split_once('/').ok_or_else(|| format!("rate limit '{}' must look like 10/60", s))?;
+        let limit: u32 = limit.trim().parse().map_err(|_| format!("bad request count in rate limit '{}'", s))?;
+        let window: u64 = window.trim().parse().map_err(|_| format!("bad window in rate limit '{}'", s))?;
+        if limit == 0 || window == 0 {
+            return Err(format!("rate limit '{}' must be non-zero", s));
+        }
+        Ok(RatePolicy { limit, window })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    policy: RatePolicy,
+}
+
+/// Outcome of a rate-limit check, with what clients need for the
+/// `RateLimit-*` and `Retry-After` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// Seconds until the next request would be allowed.
+    pub retry_after: u64,
+}
+
+impl Decision {
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        let mut set = |name: &'static str, value: u64| {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        };
+        set("ratelimit-limit", self.limit as u64);
+        set("ratelimit-remaining", self.remaining as u64);
+        set("ratelimit-reset", self.reset);
+        if !self.allowed {
+            set("retry-after", self.retry_after);
+        }
+    }
+}
+
+/// Token-bucket limiter. Buckets live in a `DashMap`, which shards its locks,
+/// so concurrent clients don't contend on a single mutex. Each bucket holds
+/// up to `limit` tokens and refills continuously at `limit / window` per second.
 pub struct RateLimiter {
-    clients: Arc<Mutex<HashMap<String, Vec<Instant>>>>,
-    limit: usize,
-    window: u64, // seconds
+    buckets: DashMap<String, Bucket>,
+    default_policy: RatePolicy,
+    route_policies: HashMap<String, RatePolicy>,
+    key_policies: HashMap<ClientId, RatePolicy>,
+    known_keys: HashSet<ClientId>,
+    rejections: DashMap<String, u64>,
 }
 
 impl RateLimiter {
-    pub fn new(limit: usize, window: u64) -> Self {
+    pub fn new(
+        default_policy: RatePolicy,
+        route_policies: HashMap<String, RatePolicy>,
+        key_policies: HashMap<ClientId, RatePolicy>,
+    ) -> Self {
         RateLimiter {
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            limit,
-            window,
+            buckets: DashMap::new(),
+            default_policy,
+            route_policies,
+            known_keys: key_policies.keys().cloned().collect(),
+            key_policies,
+            rejections: DashMap::new(),
         }
     }
 
-    pub fn allow(&self, ip: &str) -> bool {
-        let mut clients = self.clients.lock().unwrap();
+    /// Accounts these API keys per key even without a rate policy of their
+    /// own, e.g. keys that only carry a token quota or authenticate.
+    pub fn recognize_keys(mut self, keys: impl IntoIterator<Item = ClientId>) -> Self {
+        self.known_keys.extend(keys);
+        self
+    }
+
+    /// Requests carrying a configured API key are accounted per key;
+    /// everyone else per IP. Unknown keys fall back to the IP so clients
+    /// can't dodge limits by inventing keys.
+    pub fn identify(&self, ip: &str, api_key: Option<&str>) -> ClientId {
+        api_key.map(ClientId::for_key)
+            .filter(|id| self.known_keys.contains(id))
+            .unwrap_or_else(|| ClientId::for_ip(ip))
+    }
+
+    /// Takes a token for `route` on behalf of a client, under its key's
+    /// policy if it has one and the route's otherwise.
+    pub fn check(&self, route: &str, client: &ClientId) -> Decision {
+        let policy = self.key_policies.get(client)
+            .or_else(|| self.route_policies.get(route))
+            .copied()
+            .unwrap_or(self.default_policy);
         let now = Instant::now();
-        let window = Duration::from_secs(self.window);
-        let entry = clients.entry(ip.to_string()).or_insert_with(Vec::new);
-        entry.retain(|&t| now.duration_since(t) < window);
-        if entry.len() < self.limit {
-            entry.push(now);
-            true
+        let mut bucket = self.buckets.entry(format!("{}|{}", route, client.as_str())).or_insert_with(|| Bucket {
+            tokens: policy.limit as f64,
+            last: now,
+            policy,
+        });
+        let rate = bucket.policy.refill_per_sec();
+        let capacity = bucket.policy.limit as f64;
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate).min(capacity);
+        bucket.last = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
         } else {
-            false
+            *self.rejections.entry(route.to_string()).or_default() += 1;
+        }
+        Decision {
+            allowed,
+            limit: bucket.policy.limit,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: ((1.0 - bucket.tokens).max(0.0) / rate).ceil() as u64,
         }
     }
+
+    /// Drops buckets that have been idle long enough to refill completely;
+    /// recreating them later gives the same result.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, b| now.duration_since(b.last) < Duration::from_secs(b.policy.window));
+    }
+
+    /// Requests refused so far, per route.
+    pub fn rejections(&self) -> Vec<(String, u64)> {
+        self.rejections.iter().map(|r| (r.key().clone(), *r.value())).collect()
+    }
+
+    /// Distinct clients holding a bucket on any route.
+    pub fn client_count(&self) -> usize {
+        self.buckets.iter()
+            .filter_map(|b| b.key().split_once('|').map(|(_, client)| client.to_string()))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Runs `purge_idle` every `every` on the current runtime.
+    pub fn spawn_cleanup(self: &Arc<Self>, every: Duration) {
+        let limiter = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                limiter.purge_idle();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(s: &str) -> RatePolicy {
+        s.parse().unwrap()
+    }
+
+    /// Moves a bucket's last refill back in time, as if `elapsed` had passed.
+    fn age(limiter: &RateLimiter, route: &str, client: &ClientId, elapsed: Duration) {
+        let mut bucket = limiter.buckets.get_mut(&format!("{}|{}", route, client.as_str())).unwrap();
+        bucket.last -= elapsed;
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(policy("10/60"), RatePolicy { limit: 10, window: 60 });
+        assert!("10".parse::<RatePolicy>().is_err());
+        assert!("0/60".parse::<RatePolicy>().is_err());
+        assert!("10/0".parse::<RatePolicy>().is_err());
+    }
+
+    #[test]
+    fn allows_a_burst_up_to_the_limit() {
+        let limiter = RateLimiter::new(policy("3/60"), HashMap::new(), HashMap::new());
+        let client = ClientId::for_ip("10.0.0.1");
+        let remaining: Vec<u32> = (0..3).map(|_| limiter.check("chat", &client)).inspect(|d| assert!(d.allowed)).map(|d| d.remaining).collect();
+        assert_eq!(remaining, [2, 1, 0]);
+        let refused = limiter.check("chat", &client);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, 20);
+        assert_eq!(limiter.rejections(), [("chat".to_string(), 1)]);
+    }
+
+    #[test]
+    fn refills_over_the_window() {
+        let limiter = RateLimiter::new(policy("3/60"), HashMap::new(), HashMap::new());
+        let client = ClientId::for_ip("10.0.0.1");
+        for _ in 0..3 {
+            limiter.check("chat", &client);
+        }
+        age(&limiter, "chat", &client, Duration::from_secs(20));
+        assert!(limiter.check("chat", &client).allowed);
+        assert!(!limiter.check("chat", &client).allowed);
+        // Never refills past the limit
+        age(&limiter, "chat", &client, Duration::from_secs(600));
+        assert_eq!(limiter.check("chat", &client).remaining, 2);
+    }
+
+    #[test]
+    fn routes_and_clients_have_separate_buckets() {
+        let routes = HashMap::from([("chat".to_string(), policy("1/60"))]);
+        let limiter = RateLimiter::new(policy("5/60"), routes, HashMap::new());
+        let a = ClientId::for_ip("10.0.0.1");
+        let b = ClientId::for_ip("10.0.0.2");
+        assert!(limiter.check("chat", &a).allowed);
+        assert!(!limiter.check("chat", &a).allowed);
+        assert!(limiter.check("chat", &b).allowed);
+        assert_eq!(limiter.check("usage", &a).limit, 5);
+        assert_eq!(limiter.client_count(), 2);
+    }
+
+    #[test]
+    fn key_policy_overrides_the_route() {
+        let key = ClientId::for_key("s3cret");
+        let routes = HashMap::from([("chat".to_string(), policy("1/60"))]);
+        let limiter = RateLimiter::new(policy("5/60"), routes, HashMap::from([(key.clone(), policy("100/60"))]));
+        assert_eq!(limiter.identify("10.0.0.1", Some("s3cret")), key);
+        assert_eq!(limiter.check("chat", &key).limit, 100);
+    }
+
+    #[test]
+    fn unknown_keys_fall_back_to_the_ip() {
+        let limiter = RateLimiter::new(policy("5/60"), HashMap::new(), HashMap::new())
+            .recognize_keys([ClientId::for_key("known")]);
+        assert_eq!(limiter.identify("10.0.0.1", Some("made-up")), ClientId::for_ip("10.0.0.1"));
+        assert_eq!(limiter.identify("10.0.0.1", None), ClientId::for_ip("10.0.0.1"));
+        assert_eq!(limiter.identify("10.0.0.1", Some("known")), ClientId::for_key("known"));
+    }
+
+    #[test]
+    fn purges_only_refilled_buckets() {
+        let limiter = RateLimiter::new(policy("5/60"), HashMap::new(), HashMap::new());
+        let idle = ClientId::for_ip("10.0.0.1");
+        let busy = ClientId::for_ip("10.0.0.2");
+        limiter.check("chat", &idle);
+        limiter.check("chat", &busy);
+        age(&limiter, "chat", &idle, Duration::from_secs(60));
+        limiter.purge_idle();
+        assert_eq!(limiter.client_count(), 1);
+    }
 }
@@ -0,0 +1,60 @@
+use actix_web::web::Bytes;
+use serde_json::Value;
+
+/// Splits an upstream byte stream into lines.
+/// Chunks may end mid-line, so incomplete lines are buffered until the next push.
+#[derive(Default)]
+pub struct LineDecoder {
+    buf: Vec<u8>,
+}
+
+impl LineDecoder {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            lines.push(to_line(&line));
+        }
+        lines
+    }
+
+    /// Flushes a trailing line the upstream did not terminate with a newline.
+    pub fn finish(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.buf);
+        (!line.is_empty()).then(|| to_line(&line))
+    }
+}
+
+fn to_line(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw).trim_end_matches(['\r', '\n']).to_string()
+}
+
+/// Returns the payload of an SSE `data:` line.
+pub fn data_payload(line: &str) -> Option<&str> {
+    line.strip_prefix("data:").map(str::trim_start)
+}
+
+/// Encodes a named Server-Sent Event with a JSON payload.
+pub fn event(name: &str, data: &Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Encodes an unnamed event, as OpenAI-style streams use.
+pub fn data(data: &Value) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", data))
+}
+
+/// Response headers for an event stream; buffering is disabled so proxies
+/// pass tokens through as they arrive.
+pub fn response() -> actix_web::HttpResponseBuilder {
+    let mut builder = actix_web::HttpResponse::Ok();
+    builder
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"));
+    builder
+}
+
+/// Terminator of an OpenAI-style stream.
+pub const DONE: &[u8] = b"data: [DONE]\n\n";
@@ -0,0 +1,238 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use crate::config::AppConfig;
+use crate::providers::Usage;
+
+/// Header carrying the request id, taken from the client when it sends a
+/// sane one and generated otherwise.
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// How log lines are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with event fields at the top level.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}' (text or json)", other)),
+        }
+    }
+}
+
+/// A field of the per-request access log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogField {
+    RequestId,
+    ClientIp,
+    Method,
+    Route,
+    Status,
+    LatencyMs,
+    Model,
+    Cache,
+    Tokens,
+}
+
+impl FromStr for LogField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_ascii_lowercase()))
+            .map_err(|_| format!("unknown log field '{}'", s))
+    }
+}
+
+/// What a handler learns about its request that the access log reports:
+/// filled in while the request runs, read once the response is ready.
+#[derive(Default)]
+pub struct RequestNotes {
+    pub model: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+struct RequestContext {
+    id: String,
+    notes: Rc<RefCell<RequestNotes>>,
+}
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// Installs the global subscriber: log lines on stderr (including `log`
+/// records from dependencies) at the configured levels, plus span export
+/// over OTLP/HTTP when a collector is configured.
+pub fn init(config: &AppConfig) -> Result<(), TraceError> {
+    let fmt = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().flatten_event(true).boxed(),
+    };
+    let fmt = fmt.with_filter(log_filter(config));
+    // Spans are exported regardless of RUST_LOG, which only governs what is printed
+    let otlp = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+                .with_trace_config(trace::config().with_resource(Resource::new([
+                    KeyValue::new("service.name", config.service_name.clone()),
+                    KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+                ])))
+                .install_batch(runtime::TokioCurrentThread)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer).with_filter(LevelFilter::INFO))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry().with(fmt).with(otlp).init();
+    Ok(())
+}
+
+/// `log_level` with the per-module overrides, unless `RUST_LOG` is set,
+/// which replaces both for ad-hoc debugging.
+fn log_filter(config: &AppConfig) -> EnvFilter {
+    if let Ok(directives) = std::env::var(EnvFilter::DEFAULT_ENV).map(|d| d.trim().to_string()) {
+        if !directives.is_empty() {
+            return EnvFilter::new(directives);
+        }
+    }
+    let mut modules: Vec<_> = config.log_modules.iter().collect();
+    modules.sort();
+    let directives = std::iter::once(config.log_level.clone())
+        .chain(modules.into_iter().map(|(module, level)| format!("{}={}", module, level)))
+        .collect::<Vec<_>>()
+        .join(",");
+    EnvFilter::new(directives)
+}
+
+/// Flushes spans still waiting to be exported.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The client's request id if it is short and printable, else a new one.
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers.get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// Runs `fut` as the request `id`: upstream calls forward the id, and
+/// handlers' notes land in `notes`.
+pub async fn in_request<F: std::future::Future>(id: String, notes: Rc<RefCell<RequestNotes>>, fut: F) -> F::Output {
+    REQUEST.scope(RequestContext { id, notes }, fut).await
+}
+
+/// Id of the request being handled, outside of it `None`.
+pub fn current_request_id() -> Option<String> {
+    REQUEST.try_with(|r| r.id.clone()).ok()
+}
+
+/// Notes the model serving the current request. Does nothing outside one,
+/// e.g. in tasks relaying a stream after the response started.
+pub fn note_model(name: &str) {
+    let _ = REQUEST.try_with(|r| r.notes.borrow_mut().model = Some(name.to_string()));
+}
+
+/// Notes the tokens the current request used, like `note_model`.
+pub fn note_usage(usage: &Usage) {
+    let _ = REQUEST.try_with(|r| r.notes.borrow_mut().usage = Some(usage.clone()));
+}
+
+/// A request's access log line, with only the configured fields.
+pub struct AccessLog<'a> {
+    pub request_id: &'a str,
+    pub client_ip: &'a str,
+    pub method: &'a str,
+    pub route: &'a str,
+    pub status: u16,
+    pub latency: Duration,
+    pub cache: Option<&'a str>,
+    pub notes: &'a RequestNotes,
+}
+
+impl AccessLog<'_> {
+    pub fn emit(&self, fields: &[LogField]) {
+        let on = |field| fields.is_empty() || fields.contains(&field);
+        let usage = self.notes.usage.as_ref().filter(|_| on(LogField::Tokens));
+        tracing::info!(
+            target: "access",
+            request_id = on(LogField::RequestId).then_some(self.request_id),
+            client_ip = on(LogField::ClientIp).then_some(self.client_ip),
+            method = on(LogField::Method).then_some(self.method),
+            route = on(LogField::Route).then_some(self.route),
+            status = on(LogField::Status).then_some(self.status),
+            latency_ms = on(LogField::LatencyMs).then_some(self.latency.as_millis() as u64),
+            model = self.notes.model.as_deref().filter(|_| on(LogField::Model)),
+            cache = self.cache.filter(|_| on(LogField::Cache)),
+            prompt_tokens = usage.map(|u| u.prompt_tokens),
+            completion_tokens = usage.map(|u| u.completion_tokens),
+            "request completed"
+        );
+    }
+}
+
+/// Continues the trace named by an incoming W3C `traceparent` header, if any.
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let cx = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    span.set_parent(cx);
+}
+
+/// Headers tying an upstream call to the current request: its id and a
+/// `traceparent` naming the current span.
+pub fn outgoing_headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    TraceContextPropagator::new().inject_context(&tracing::Span::current().context(), &mut HeaderInjector(&mut headers));
+    if let Ok(Ok(id)) = REQUEST.try_with(|r| HeaderValue::from_str(&r.id)) {
+        headers.insert(HeaderName::from_static(X_REQUEST_ID), id);
+    }
+    headers
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
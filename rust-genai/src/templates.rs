@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tera::{Context, Tera};
+use crate::assets;
+use crate::watch;
+
+/// The page templates, compiled once from the embedded copies overlaid with
+/// `dir`. In dev mode they are recompiled when the directory changes; a
+/// template that fails to compile is kept as an error for the page to show
+/// rather than taking the server down.
+pub struct Templates {
+    dir: Option<PathBuf>,
+    tera: RwLock<Result<Tera, String>>,
+}
+
+impl Templates {
+    pub fn new(dir: Option<&str>) -> Self {
+        let dir = dir.map(PathBuf::from);
+        let tera = compile(dir.as_deref());
+        Templates { dir, tera: RwLock::new(tera) }
+    }
+
+    /// The compile error from the last (re)load, if any.
+    pub fn error(&self) -> Option<String> {
+        self.tera.read().unwrap().as_ref().err().cloned()
+    }
+
+    pub fn reload(&self) {
+        let tera = compile(self.dir.as_deref());
+        match &tera {
+            Ok(t) => log::info!("Reloaded {} templates", t.get_template_names().count()),
+            Err(e) => log::error!("Template error: {}", e),
+        }
+        *self.tera.write().unwrap() = tera;
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, String> {
+        match &*self.tera.read().unwrap() {
+            Ok(tera) => tera.render(name, context).map_err(|e| describe(&e)),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Recompiles the templates whenever the directory changes, checking every `every`.
+    pub fn spawn_watcher(self: &Arc<Self>, every: Duration) {
+        let Some(dir) = self.dir.clone() else { return };
+        let templates = Arc::clone(self);
+        watch::spawn_poller(dir, every, move || templates.reload());
+    }
+}
+
+fn compile(dir: Option<&Path>) -> Result<Tera, String> {
+    let mut tera = match dir {
+        Some(dir) => Tera::new(&format!("{}/**/*", dir.display())).map_err(|e| describe(&e))?,
+        None => Tera::default(),
+    };
+    let mut embedded = Tera::default();
+    embedded.add_raw_templates(assets::embedded_templates()).map_err(|e| describe(&e))?;
+    tera.extend(&embedded).map_err(|e| describe(&e))?;
+    Ok(tera)
+}
+
+/// Tera's top-level message only names the template; the cause is further
+/// down the error chain.
+fn describe(error: &tera::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!("\n  caused by: {}", cause));
+        source = cause.source();
+    }
+    message.trim().to_string()
+}
+
+/// A bare page showing a template error, for dev mode.
+pub fn error_page(error: &str) -> String {
+    let escaped = error.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    format!(
+        "<!DOCTYPE html><html><head><title>Template error</title></head>\
+         <body><h1>Template error</h1><pre>{}</pre><p>Fix the template and reload.</p></body></html>",
+        escaped
+    )
+}
@@ -0,0 +1,53 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Hash of the names, sizes and modification times of every file under
+/// `dir`, so any edit, addition or removal changes it.
+pub fn fingerprint(dir: &Path) -> u64 {
+    let mut files = Vec::new();
+    collect(dir, &mut files);
+    files.sort();
+    let mut hasher = DefaultHasher::new();
+    for path in files {
+        let meta = std::fs::metadata(&path).ok();
+        path.hash(&mut hasher);
+        meta.as_ref().map(|m| m.len()).hash(&mut hasher);
+        meta.and_then(|m| m.modified().ok()).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Polls `dir` every `every` on the current runtime and calls `on_change`
+/// when its fingerprint moves. Polling rather than OS file events keeps it
+/// working on bind mounts and network filesystems.
+pub fn spawn_poller<F>(dir: PathBuf, every: Duration, on_change: F)
+where
+    F: Fn() + 'static,
+{
+    actix_web::rt::spawn(async move {
+        let mut last = fingerprint(&dir);
+        let mut interval = actix_web::rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let current = fingerprint(&dir);
+            if current != last {
+                last = current;
+                on_change();
+            }
+        }
+    });
+}